@@ -0,0 +1,478 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use super::{device, Device, StkError};
+use super::isp::{IspOp, Opcode};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(u32),
+    Equals,
+    Comma,
+    /// Separates flags, as in `prog_modes = PM_SPM | PM_ISP;`
+    Bar,
+    Semicolon,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Ident(String),
+    Str(String),
+    Number(u32),
+}
+
+/// The statements of a `part` or `memory` block.
+#[derive(Clone, Debug, Default)]
+struct Block {
+    parent: Option<String>,
+    values: HashMap<String, Vec<Value>>,
+    memories: HashMap<String, Block>,
+}
+
+impl Block {
+    /// Apply `child`'s statements on top of this block's.
+    fn inherit(&self, child: &Block) -> Block {
+        let mut block = self.clone();
+        block.parent = child.parent.clone();
+        for (key, values) in child.values.iter() {
+            block.values.insert(key.clone(), values.clone());
+        }
+        for (name, memory) in child.memories.iter() {
+            let merged = match block.memories.get(name) {
+                Some(parent) => parent.inherit(memory),
+                None => memory.clone(),
+            };
+            block.memories.insert(name.clone(), merged);
+        }
+        block
+    }
+
+    fn number(&self, key: &str) -> Option<u32> {
+        match self.values.get(key).and_then(|v| v.first()) {
+            Some(&Value::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn string(&self, key: &str) -> Option<&str> {
+        match self.values.get(key).and_then(|v| v.first()) {
+            Some(Value::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn ident(&self, key: &str) -> Option<&str> {
+        match self.values.get(key).and_then(|v| v.first()) {
+            Some(Value::Ident(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn memory_number(&self, memory: &str, key: &str) -> Option<u32> {
+        self.memories.get(memory).and_then(|m| m.number(key))
+    }
+
+    /// The statements whose value is an ISP instruction template, as
+    /// operations on `memory`.
+    fn opcodes(&self, memory: &str) -> Vec<IspOp> {
+        let mut ops = Vec::new();
+        for (key, values) in self.values.iter() {
+            let template = values.iter()
+                .map(|value| match *value {
+                    Value::Str(ref s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|parts| parts.join(" "));
+            if let Some(template) = template {
+                if Opcode::parse(&template).is_ok() {
+                    ops.push(IspOp {
+                        memory: Cow::Owned(memory.to_string()),
+                        operation: Cow::Owned(key.clone()),
+                        template: Cow::Owned(template),
+                    });
+                }
+            }
+        }
+        ops
+    }
+}
+
+/// Parse the `part` definitions of an avrdude.conf file into devices.
+/// `parent` inheritance is resolved. Parts without a signature, such as the
+/// templates other parts inherit from, are left out.
+///
+/// Parts with no `stk500_devcode` take the device code of the built-in part
+/// with the same signature. Those without one are left out too: the STK500
+/// picks its programming algorithm by device code, so a guessed one isn't
+/// safe. See `parse_with_devicecodes` to supply them.
+pub fn parse(conf: &str) -> Result<Vec<Device>, StkError> {
+    parse_with_devicecodes(conf, |_| None)
+}
+
+/// Like `parse`, with `devicecode` asked for the device code of each part,
+/// by id, that has neither a `stk500_devcode` nor a built-in counterpart.
+/// Parts it returns `None` for are left out.
+pub fn parse_with_devicecodes<F>(conf: &str, devicecode: F) -> Result<Vec<Device>, StkError>
+    where F: Fn(&str) -> Option<u8>
+{
+    let tokens = tokenize(conf)?;
+    let mut parser = Parser { tokens, pos: 0, line: 1 };
+    let mut parts: Vec<Block> = Vec::new();
+    let mut by_id: HashMap<String, usize> = HashMap::new();
+
+    while let Some((line, token)) = parser.next() {
+        let keyword = match token {
+            Token::Ident(keyword) => keyword,
+            _ => return Err(conf_error(line, "expected a keyword")),
+        };
+        // Top level settings such as `default_programmer = "...";`
+        if parser.peek() == Some(&Token::Equals) {
+            parser.next();
+            parser.values()?;
+            continue;
+        }
+        let block = parser.block()?;
+        if keyword != "part" {
+            continue;
+        }
+        let block = match block.parent {
+            Some(ref parent) => {
+                let &index = by_id.get(parent).ok_or_else(|| conf_error(line, "unknown parent part"))?;
+                parts[index].inherit(&block)
+            }
+            None => block,
+        };
+        if let Some(id) = block.string("id") {
+            by_id.insert(id.to_string(), parts.len());
+        }
+        parts.push(block);
+    }
+
+    Ok(parts.iter().filter_map(|part| to_device(part, &devicecode)).collect())
+}
+
+fn to_device(part: &Block, substitute: &dyn Fn(&str) -> Option<u8>) -> Option<Device> {
+    let signature = match part.values.get("signature") {
+        Some(values) if values.len() == 3 => {
+            let mut signature = [0; 3];
+            for (byte, value) in signature.iter_mut().zip(values.iter()) {
+                match *value {
+                    Value::Number(n) => *byte = n as u8,
+                    _ => return None,
+                }
+            }
+            signature
+        }
+        _ => return None,
+    };
+    let flash_size = part.memory_number("flash", "size").unwrap_or(0);
+    let flash_page_size = part.memory_number("flash", "page_size").unwrap_or(0) as u16;
+    let id = part.string("id").unwrap_or("").to_string();
+    let devicecode = match part.number("stk500_devcode") {
+        Some(devicecode) => devicecode as u8,
+        None => device::by_signature(signature).map(|d| d.devicecode).or_else(|| substitute(&id))?,
+    };
+    let name = part.string("desc").map_or_else(|| id.clone(), |s| s.to_string());
+    let fuse_bytes = part.memories.iter()
+        .filter(|&(name, _)| name.ends_with("fuse"))
+        .map(|(_, memory)| memory.number("size").unwrap_or(1))
+        .sum::<u32>();
+    let mut opcodes = part.opcodes("");
+    for (name, memory) in part.memories.iter() {
+        opcodes.extend(memory.opcodes(name));
+    }
+    opcodes.sort_by(|a, b| (&a.memory, &a.operation).cmp(&(&b.memory, &b.operation)));
+    let poll = |memory: &str| [
+        part.memory_number(memory, "readback_p1").unwrap_or(0xff) as u8,
+        part.memory_number(memory, "readback_p2").unwrap_or(0xff) as u8,
+    ];
+    Some(Device {
+        id: Cow::Owned(id),
+        name: Cow::Owned(name),
+        signature,
+        devicecode,
+        flash_size,
+        flash_page_size,
+        eeprom_size: part.memory_number("eeprom", "size").unwrap_or(0) as u16,
+        eeprom_page_size: part.memory_number("eeprom", "page_size").unwrap_or(0) as u8,
+        fuse_bytes: fuse_bytes as u8,
+        lock_bytes: part.memory_number("lock", "size").unwrap_or(0) as u8,
+        flash_poll: poll("flash"),
+        eeprom_poll: poll("eeprom"),
+        pagel: part.number("pagel").unwrap_or(0) as u8,
+        bs2: part.number("bs2").unwrap_or(0) as u8,
+        reset_disable: part.ident("reset") == Some("io"),
+        chip_erase_delay: part.number("chip_erase_delay").unwrap_or(0),
+        opcodes: Cow::Owned(opcodes),
+    })
+}
+
+struct Parser {
+    // Tokens with the line they were found on
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    // Line of the last token read, for error reporting
+    line: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.pos).cloned();
+        if let Some((line, _)) = token {
+            self.line = line;
+        }
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    /// The body of a block, up to and including its closing ';'.
+    fn block(&mut self) -> Result<Block, StkError> {
+        let mut block = Block::default();
+        if self.peek() == Some(&Token::Ident("parent".to_string())) {
+            self.next();
+            match self.next() {
+                Some((_, Token::Str(parent))) => block.parent = Some(parent),
+                _ => return Err(conf_error(self.line, "expected a parent part id")),
+            }
+        }
+        loop {
+            match self.next() {
+                Some((_, Token::Semicolon)) => return Ok(block),
+                Some((_, Token::Ident(ref keyword))) if keyword == "memory" => {
+                    let name = match self.next() {
+                        Some((_, Token::Str(name))) => name,
+                        _ => return Err(conf_error(self.line, "expected a memory name")),
+                    };
+                    let memory = self.block()?;
+                    block.memories.insert(name, memory);
+                }
+                Some((_, Token::Ident(key))) => {
+                    match self.next() {
+                        Some((_, Token::Equals)) => {}
+                        _ => return Err(conf_error(self.line, "expected '='")),
+                    }
+                    let values = self.values()?;
+                    block.values.insert(key, values);
+                }
+                Some((line, _)) => return Err(conf_error(line, "expected a keyword or ';'")),
+                None => return Err(conf_error(self.line, "unterminated block")),
+            }
+        }
+    }
+
+    /// The values of an assignment, up to and including the closing ';'.
+    fn values(&mut self) -> Result<Vec<Value>, StkError> {
+        let mut values = Vec::new();
+        loop {
+            match self.next() {
+                Some((_, Token::Semicolon)) => return Ok(values),
+                Some((_, Token::Comma)) | Some((_, Token::Bar)) => {}
+                Some((_, Token::Ident(s))) => values.push(Value::Ident(s)),
+                Some((_, Token::Str(s))) => values.push(Value::Str(s)),
+                Some((_, Token::Number(n))) => values.push(Value::Number(n)),
+                Some((line, Token::Equals)) => return Err(conf_error(line, "unexpected '='")),
+                None => return Err(conf_error(self.line, "missing ';'")),
+            }
+        }
+    }
+}
+
+fn tokenize(conf: &str) -> Result<Vec<(usize, Token)>, StkError> {
+    let mut tokens = Vec::new();
+    for (line_no, line) in conf.lines().enumerate() {
+        let line_no = line_no + 1;
+        let mut chars = line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c == '#' {
+                break;
+            } else if c.is_whitespace() {
+                chars.next();
+            } else if c == '=' || c == ',' || c == '|' || c == ';' {
+                chars.next();
+                tokens.push((line_no, match c {
+                    '=' => Token::Equals,
+                    ',' => Token::Comma,
+                    '|' => Token::Bar,
+                    _ => Token::Semicolon,
+                }));
+            } else if c == '"' {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err(conf_error(line_no, "unterminated string")),
+                    }
+                }
+                tokens.push((line_no, Token::Str(s)));
+            } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' {
+                        word.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push((line_no, word_token(word, line_no)?));
+            } else {
+                return Err(conf_error(line_no, "unexpected character"));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn word_token(word: String, line: usize) -> Result<Token, StkError> {
+    if !word.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok(Token::Ident(word));
+    }
+    let n = if word.starts_with("0x") || word.starts_with("0X") {
+        u32::from_str_radix(&word[2..], 16)
+    } else {
+        word.parse()
+    };
+    n.map(Token::Number).map_err(|_| conf_error(line, "invalid number"))
+}
+
+fn conf_error(line: usize, reason: &'static str) -> StkError {
+    StkError::ParseConfError { line, reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_with_devicecodes};
+    use super::super::device;
+
+    #[test]
+    fn parse_parts_with_parent() {
+        let conf = r#"
+# Comment
+default_programmer = "stk500v1";
+
+programmer
+    id    = "arduino";
+    desc  = "Arduino";
+    type  = "arduino";
+;
+
+part
+    id               = "m328p";
+    desc             = "ATmega328P";
+    signature        = 0x1e 0x95 0x0f;
+    stk500_devcode   = 0x86;
+    pagel            = 0xd7;
+    bs2              = 0xc2;
+    reset            = dedicated;
+    chip_erase_delay = 9000;
+    memory "eeprom"
+        size            = 1024;
+        page_size       = 4;
+        readback_p1     = 0xff;
+        readback_p2     = 0xff;
+        read            = " 1 0 1 0 0 0 0 0",
+                          " 0 0 0 x x x a9 a8",
+                          " a7 a6 a5 a4 a3 a2 a1 a0",
+                          " o o o o o o o o";
+    ;
+    memory "flash"
+        paged           = yes;
+        size            = 32768;
+        page_size       = 128;
+        readback_p1     = 0xff;
+        readback_p2     = 0xff;
+    ;
+    memory "lfuse" size = 1; ;
+    memory "hfuse" size = 1; ;
+    memory "efuse" size = 1; ;
+    memory "lock" size = 1; ;
+;
+
+part parent "m328p"
+    id               = "m328";
+    desc             = "ATmega328";
+    signature        = 0x1e 0x95 0x14;
+    memory "flash"
+        page_size       = 64;
+    ;
+;
+"#;
+        let devices = parse(conf).unwrap();
+        assert_eq!(devices.len(), 2);
+        let m328p = device::by_name("m328p").unwrap();
+        assert_eq!(devices[0].parameters(), m328p.parameters());
+        assert_eq!(devices[0].parameters_ext(), m328p.parameters_ext());
+        assert_eq!(devices[0].chip_erase_delay, m328p.chip_erase_delay);
+        assert_eq!(devices[0].opcode("eeprom", "read").unwrap().instruction(0x1ff, 0), [0xa0, 0x01, 0xff, 0]);
+        assert_eq!(devices[1].name, "ATmega328");
+        assert_eq!(devices[1].signature, [0x1e, 0x95, 0x14]);
+        assert_eq!(devices[1].flash_size, 32768);
+        assert_eq!(devices[1].flash_page_size, 64);
+        assert_eq!(devices[1].fuse_bytes, 3);
+    }
+
+    #[test]
+    fn parse_prog_modes_and_borrowed_devicecode() {
+        let conf = r#"
+part
+    id               = "m328p";
+    desc             = "ATmega328P";
+    prog_modes       = PM_SPM | PM_ISP | PM_HVPP | PM_debugWIRE;
+    signature        = 0x1e 0x95 0x0f;
+    memory "flash"
+        size            = 32768;
+        page_size       = 128;
+    ;
+;
+
+part
+    id               = "m328pb";
+    desc             = "ATmega328PB";
+    prog_modes       = PM_SPM | PM_ISP | PM_HVPP | PM_debugWIRE;
+    signature        = 0x1e 0x95 0x16;
+    memory "flash"
+        size            = 32768;
+        page_size       = 128;
+    ;
+;
+
+part
+    id               = "t4";
+    desc             = "ATtiny4";
+    prog_modes       = PM_TPI;
+    signature        = 0x1e 0x8f 0x0a;
+    memory "flash"
+        size            = 512;
+        page_size       = 16;
+    ;
+;
+"#;
+        // Only the part with a built-in counterpart, even though the
+        // ATmega328PB's flash is laid out like the ATmega328P's
+        let devices = parse(conf).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "ATmega328P");
+        assert_eq!(devices[0].devicecode, 0x86);
+
+        let devices = parse_with_devicecodes(conf, |id| if id == "m328pb" { Some(0x87) } else { None }).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].name, "ATmega328PB");
+        assert_eq!(devices[1].devicecode, 0x87);
+    }
+
+    #[test]
+    fn parse_error_line() {
+        match parse("part\n    id = \"m328p\";\n    signature = ;\n    memory flash\n;\n") {
+            Err(super::StkError::ParseConfError { line: 4, .. }) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
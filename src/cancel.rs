@@ -0,0 +1,39 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Stops a programming session from another task or thread. Clones share the
+/// same flag.
+///
+/// A cancelled session stops before its next step, be it the chip erase or
+/// the next page, takes the target out of programming mode and fails with
+/// `StkError::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CancelHandle;
+
+    #[test]
+    fn clones_share_the_flag() {
+        let handle = CancelHandle::new();
+        let clone = handle.clone();
+        assert!(!handle.is_cancelled());
+        clone.cancel();
+        assert!(handle.is_cancelled());
+    }
+}
@@ -1,11 +1,19 @@
 use bytes::{BytesMut};
-use futures::future::{Either, Future, loop_fn, Loop};
+use futures::future::{self, Either, Future, loop_fn, Loop};
 use futures_timer;
-use super::Command;
+use super::{device, fuse, Command, Device, DeviceParameters, DeviceParametersExt, Fuses, Parameter, Version, ElfImage, MemoryImage, StkError, RESYNC_ATTEMPTS, RESYNC_REPLIES, resync_backoff};
+use super::isp::Opcode;
+use super::cancel::CancelHandle;
+use super::progress::{report, Progress, ProgressCallback};
 use super::tokio_io::{AsyncWrite, AsyncRead};
 use super::tokio_io::codec::{Encoder, Decoder, Framed};
+use std::cell::Cell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
 use std::io;
 use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use tokio_core;
 use tokio_core::reactor::{Handle};
@@ -13,8 +21,142 @@ use tokio_proto::pipeline::{ClientProto, ClientService};
 use tokio_proto::{BindClient};
 use tokio_service::{Service};
 
-pub type ResponseFuture = Box<Future<Item = BytesMut, Error = io::Error>>;
+pub type ResponseFuture = Box<dyn Future<Item = BytesMut, Error = StkError>>;
 
+/// How long to wait after writing a byte with an ISP instruction
+const ISP_WRITE_DELAY: Duration = Duration::from_millis(10);
+
+/// First byte of the ISP instruction selecting the 64K word bank of flash
+/// that `CmndStkLoadAddress` addresses
+const LOAD_EXT_ADDR: u8 = 0x4d;
+
+/// Options for a `Client::prog_memory_with` programming session.
+#[derive(Clone, Debug, Default)]
+pub struct ProgOptions {
+    /// Read back every page after it is written and fail on the first byte
+    /// that doesn't match.
+    pub verify: bool,
+    /// The part being programmed. Its parameters are sent to the programmer
+    /// and its signature is checked, unless `signature` says otherwise. The
+    /// programmer is set up for an ATmega328P if this is left out.
+    pub device: Option<Device>,
+    /// Abort before anything is written unless the target's signature is
+    /// this one.
+    pub signature: Option<[u8; 3]>,
+    /// Erase the chip before writing. This also clears the lock bits.
+    pub erase: bool,
+    /// When programming EEPROM, read the target's OSCCAL calibration byte
+    /// and write it at this EEPROM address, in place of whatever the data
+    /// has there. Ignored for other memories.
+    pub osccal_address: Option<u32>,
+    /// Ask the target with `CmndStkCheckAutoinc` whether it moves the
+    /// address on after each page by itself, and if it does, skip loading
+    /// the address for pages that follow straight on from the last one.
+    /// Leave this off for Optiboot and its relatives, which acknowledge
+    /// commands they don't know without acting on them.
+    pub autoinc: bool,
+    /// Called as the session goes along, e.g. after every page.
+    pub progress: Option<ProgressCallback>,
+    /// Lets the session be stopped between pages.
+    pub cancel: Option<CancelHandle>,
+    /// Have `prog_elf_with` write the ELF file's fuse and lock bytes too,
+    /// after flash and EEPROM. Fuse values that would lock an ISP programmer
+    /// out are refused before anything is written. Ignored by
+    /// `prog_memory_with`.
+    pub fuses: bool,
+}
+
+/// Timing and retry settings for a `Client`.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// How long to wait for a reply to a command, or `None` to wait as long
+    /// as it takes. A command that times out only fails after waiting as long
+    /// again, so that a late reply can't be taken for the reply to the next
+    /// command.
+    pub timeout: Option<Duration>,
+    /// Timeouts for particular commands, in place of `timeout`. Slow
+    /// operations like `CmndStkChipErase` or EEPROM page writes may need
+    /// longer ones.
+    pub command_timeouts: Vec<(Command, Option<Duration>)>,
+    /// How long `prog_memory` waits before writing each page
+    pub page_delay: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl ClientConfig {
+    /// The timeout for `command`
+    pub fn timeout_for(&self, command: Command) -> Option<Duration> {
+        self.command_timeouts.iter()
+            .find(|&&(c, _)| c == command)
+            .map_or(self.timeout, |&(_, timeout)| timeout)
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            timeout: Some(Duration::from_millis(500)),
+            command_timeouts: Vec::new(),
+            page_delay: Duration::from_millis(50),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Which failed commands are sent again, and how often. The target is
+/// resynced with `CmndStkGetSync` before each retry.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per command, counting the first. 1 never retries.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each one after it
+    pub backoff: Duration,
+    /// Whether this command, having failed with this error, is worth
+    /// sending again. The target may have acted on it regardless, so the
+    /// default only retries commands for which `is_idempotent` holds, after
+    /// a timeout or a loss of sync.
+    pub retryable: fn(Command, &StkError) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::from_millis(10),
+            retryable: |command, e| {
+                is_idempotent(command) && matches!(*e, StkError::Timeout | StkError::OutOfSync)
+            },
+        }
+    }
+}
+
+/// What's on the other end of the line, as reported by `Client::identify`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    /// The `CmndStkGetSignOn` reply, "AVR STK" for an STK500
+    pub sign_on: String,
+    pub hardware_version: u8,
+    pub firmware_version: Version,
+    pub signature: [u8; 3],
+}
+
+impl Identity {
+    /// The target part, if its signature is a known one
+    pub fn device(&self) -> Option<&'static Device> {
+        device::by_signature(self.signature)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (hardware {}, firmware {}), target {:02x}{:02x}{:02x} ({})",
+               self.sign_on, self.hardware_version, self.firmware_version,
+               self.signature[0], self.signature[1], self.signature[2],
+               self.device().map_or("unknown part", |d| &d.name))
+    }
+}
+
+#[derive(Clone)]
 pub struct Packet {
     command: Command,
     payload: Vec<u8>,
@@ -39,8 +181,12 @@ impl<T> Client<T>
     where T: AsyncRead + AsyncWrite + 'static
 {
     pub fn new(handle: &Handle, io_transport: T) -> Client<T> {
+        Client::with_config(handle, io_transport, ClientConfig::default())
+    }
+
+    pub fn with_config(handle: &Handle, io_transport: T, config: ClientConfig) -> Client<T> {
         Client{ 
-            inner: Arc::new( Inner::new(handle, io_transport) )
+            inner: Arc::new( Inner::new(handle, io_transport, config) )
         }
     }
     
@@ -49,13 +195,103 @@ impl<T> Client<T>
         self.inner.get_sync()
     }
 
-    pub fn set_device(&self, payload: &Option<Vec<u8>>) -> ResponseFuture {
+    /// Get back in sync with the target after a framing error or a reset.
+    ///
+    /// `CmndStkGetSync` is sent repeatedly, backing off after each failure,
+    /// until `RESYNC_REPLIES` consecutive in-sync replies are received.
+    pub fn resync(&self) -> Box<dyn Future<Item = (), Error = StkError>> {
+        debug!("resync()");
+        resync(self.inner.clone())
+    }
+
+    /// Send the part's parameters, or an ATmega328P's if `parameters` is `None`.
+    pub fn set_device(&self, parameters: &Option<DeviceParameters>) -> ResponseFuture {
         debug!("set_device()");
-        self.inner.set_device(payload)
+        self.inner.set_device(parameters)
     }
 
-    pub fn set_device_ext(&self, payload: &Option<Vec<u8>>) -> ResponseFuture {
-        self.inner.set_device_ext(payload)
+    pub fn set_device_ext(&self, parameters: &Option<DeviceParametersExt>) -> ResponseFuture {
+        self.inner.set_device_ext(parameters)
+    }
+
+    /// Ask the programmer to identify itself. An STK500 answers "AVR STK".
+    pub fn sign_on(&self) -> Box<dyn Future<Item = String, Error = StkError>> {
+        debug!("sign_on()");
+        Box::new(self.inner.sign_on().map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    /// Gather the programmer's sign-on reply and versions, and the target's
+    /// signature.
+    ///
+    /// The target is put into programming mode to read its signature, and
+    /// taken out of it again before this resolves.
+    pub fn identify(&self) -> Box<dyn Future<Item = Identity, Error = StkError>> {
+        let step0 = self.resync();
+
+        let client = self.clone();
+        let step1 = move |_| {
+            client.sign_on()
+        };
+
+        let client = self.clone();
+        let step2 = move |sign_on| {
+            client.hardware_version().map(move |hw| (sign_on, hw))
+        };
+
+        let client = self.clone();
+        let step3 = move |(sign_on, hw)| {
+            client.firmware_version().map(move |fw| (sign_on, hw, fw))
+        };
+
+        let inner = self.inner.clone();
+        let step4 = move |(sign_on, hardware_version, firmware_version)| {
+            read_signature(inner).map(move |signature| Identity {
+                sign_on, hardware_version, firmware_version, signature,
+            })
+        };
+
+        let f = step0
+            .and_then( step1 )
+            .and_then( step2 )
+            .and_then( step3 )
+            .and_then( step4 );
+
+        Box::new(f)
+    }
+
+    /// Read one of the programmer's parameters.
+    pub fn get_parameter(&self, parameter: Parameter) -> Box<dyn Future<Item = u8, Error = StkError>> {
+        debug!("get_parameter({:?})", parameter);
+        Box::new(self.inner.get_parameter(parameter).map(|value| value[0]))
+    }
+
+    pub fn set_parameter(&self, parameter: Parameter, value: u8) -> ResponseFuture {
+        debug!("set_parameter({:?}, {})", parameter, value);
+        self.inner.set_parameter(parameter, value)
+    }
+
+    pub fn hardware_version(&self) -> Box<dyn Future<Item = u8, Error = StkError>> {
+        self.get_parameter(Parameter::HwVer)
+    }
+
+    /// The version of the programmer's firmware, or of the bootloader when
+    /// talking to one directly.
+    pub fn firmware_version(&self) -> Box<dyn Future<Item = Version, Error = StkError>> {
+        let client = self.clone();
+        Box::new(self.get_parameter(Parameter::SwMajor).and_then(move |major| {
+            client.get_parameter(Parameter::SwMinor).map(move |minor| Version { major, minor })
+        }))
+    }
+
+    /// The target voltage measured by the programmer, in volts.
+    pub fn target_voltage(&self) -> Box<dyn Future<Item = f32, Error = StkError>> {
+        Box::new(self.get_parameter(Parameter::Vtarget).map(|v| v as f32 / 10.0))
+    }
+
+    /// Set the ISP clock period, in the programmer's own units. Larger values
+    /// give a slower SCK, which targets running from a slow clock need.
+    pub fn set_sck_duration(&self, duration: u8) -> ResponseFuture {
+        self.set_parameter(Parameter::SckDuration, duration)
     }
 
     pub fn enter_prog_mode(&self) -> ResponseFuture {
@@ -74,10 +310,94 @@ impl<T> Client<T>
         self.inner.prog_page(mem_type, data)
     }
 
-    pub fn prog_memory(&self, mem_type: char, page_size: usize, word_size: usize, data: Vec<u8>)
-        -> ResponseFuture
+    /// Read `len` bytes of `mem_type` memory starting at the last loaded address.
+    pub fn read_page(&self, mem_type: char, len: u16) -> ResponseFuture {
+        self.inner.read_page(mem_type, len)
+    }
+
+    /// Write one flash word at the last loaded word address.
+    pub fn prog_flash(&self, word: u16) -> ResponseFuture {
+        self.inner.prog_flash(word)
+    }
+
+    /// Write one EEPROM byte at the last loaded address.
+    pub fn prog_data(&self, byte: u8) -> ResponseFuture {
+        self.inner.prog_data(byte)
+    }
+
+    /// Read the flash word at the last loaded word address.
+    pub fn read_flash(&self) -> Box<dyn Future<Item = u16, Error = StkError>> {
+        Box::new(self.inner.read_flash().map(|word| word[0] as u16 | (word[1] as u16) << 8))
+    }
+
+    /// Read the EEPROM byte at the last loaded address.
+    pub fn read_data(&self) -> Box<dyn Future<Item = u8, Error = StkError>> {
+        Box::new(self.inner.read_data().map(|byte| byte[0]))
+    }
+
+    /// Write `data` to `device`'s EEPROM from `address` one byte at a time,
+    /// leaving the rest of the EEPROM alone. Meant for patching a few cells,
+    /// like a serial number; use `prog_memory` for anything bigger.
+    ///
+    /// Bytes past the end of the EEPROM fail the call with
+    /// `StkError::AddressOutOfRange` before anything is written.
+    pub fn write_eeprom_bytes(&self, device: &Device, address: u16, data: &[u8]) -> ResponseFuture {
+        debug!("write_eeprom_bytes({}, {} bytes)", address, data.len());
+        if let Err(e) = check_eeprom_range(device, address, data.len()) {
+            return Box::new(future::err(e));
+        }
+        let data = data.to_vec();
+        in_prog_mode(self.inner.clone(), device, move |inner| {
+            let f = loop_fn(0, move |index| -> Box<dyn Future<Item = Loop<BytesMut, usize>, Error = StkError>> {
+                if index >= data.len() {
+                    return Box::new(future::ok(Loop::Break(BytesMut::new())));
+                }
+                let byte = data[index];
+                let _inner = inner.clone();
+                let f = inner.load_address(address + index as u16)
+                    .and_then(move |_| _inner.prog_data(byte))
+                    .map(move |_| Loop::Continue(index + 1));
+                Box::new(f)
+            });
+            Box::new(f)
+        })
+    }
+
+    /// Read `len` bytes of `device`'s EEPROM from `address`, one byte at a
+    /// time. The range must fit in the EEPROM, as for `write_eeprom_bytes`.
+    pub fn read_eeprom_bytes(&self, device: &Device, address: u16, len: u16)
+        -> Box<dyn Future<Item = Vec<u8>, Error = StkError>>
+    {
+        debug!("read_eeprom_bytes({}, {})", address, len);
+        if let Err(e) = check_eeprom_range(device, address, len as usize) {
+            return Box::new(future::err(e));
+        }
+        in_prog_mode(self.inner.clone(), device, move |inner| {
+            let f = loop_fn(Vec::with_capacity(len as usize), move |mut buf| {
+                if buf.len() >= len as usize {
+                    return Box::new(future::ok(Loop::Break(buf))) as Box<dyn Future<Item = _, Error = StkError>>;
+                }
+                let _inner = inner.clone();
+                let f = inner.load_address(address + buf.len() as u16)
+                    .and_then(move |_| _inner.read_data())
+                    .map(move |byte| {
+                        buf.push(byte[0]);
+                        Loop::Continue(buf)
+                    });
+                Box::new(f)
+            });
+            Box::new(f)
+        })
+    }
+
+    /// Read `len` bytes of `mem_type` memory starting at byte address `start`.
+    ///
+    /// The whole programming session is handled here, the same way as for
+    /// `prog_memory`.
+    pub fn read_memory(&self, mem_type: char, start: usize, len: usize, page_size: usize, word_size: usize)
+        -> Box<dyn Future<Item = Vec<u8>, Error = StkError>>
     {
-        let step0 = self.get_sync();
+        let step0 = self.resync();
 
         let inner = self.inner.clone();
         let step1 = move |_| {
@@ -93,92 +413,815 @@ impl<T> Client<T>
         let step3 = move |_| {
             inner.enter_prog_mode()
         };
+
+        let inner = self.inner.clone();
+        let step4 = move |_| {
+            read_pages(inner, mem_type, start, len, page_size, word_size)
+        };
+
+        let inner = self.inner.clone();
+        let step5 = move |buf| {
+            inner.leave_prog_mode().map(move |_| buf)
+        };
+
+        let f = step0
+            .and_then( step1 )
+            .and_then( step2 )
+            .and_then( step3 )
+            .and_then( step4 )
+            .and_then( step5 );
+
+        Box::new(f)
+    }
+
+    /// Read `device`'s fuse bytes. The extended fuse is read if the part has
+    /// one.
+    pub fn read_fuses(&self, device: &Device) -> Box<dyn Future<Item = Fuses, Error = StkError>> {
+        debug!("read_fuses()");
+        let extended = device.fuse_bytes > 2;
+        let table = if extended {
+            isp_table(device, "read", &[("lfuse", 0), ("hfuse", 0), ("efuse", 0)])
+        } else {
+            isp_table(device, "read", &[("lfuse", 0), ("hfuse", 0)])
+        };
+        in_prog_mode(self.inner.clone(), device, move |inner| {
+            let _inner = inner.clone();
+            let f = if extended { inner.read_fuse_ext() } else { inner.read_fuse() };
+            let f = f.map(|fuses| fuses.to_vec())
+                .or_else(move |e| isp_fallback(_inner, e, table, Duration::from_millis(0)))
+                .map(|fuses| Fuses { low: fuses[0], high: fuses[1], extended: fuses.get(2).cloned() });
+            Box::new(f)
+        })
+    }
+
+    /// Write `device`'s fuse bytes. The extended fuse is only written if
+    /// `fuses` has one.
+    ///
+    /// Values that would lock an ISP programmer out of the part are refused
+    /// with `StkError::UnsafeFuses` unless `allow_unsafe` is set, and so is
+    /// any value for a part whose fuse layout isn't known.
+    pub fn write_fuses(&self, device: &Device, fuses: Fuses, allow_unsafe: bool) -> ResponseFuture {
+        debug!("write_fuses({:?})", fuses);
+        if !allow_unsafe {
+            if let Err(e) = fuse::check_fuses(device, &fuses) {
+                return Box::new(future::err(e));
+            }
+        }
+        let table = match fuses.extended {
+            Some(extended) => isp_table(device, "write",
+                                        &[("lfuse", fuses.low), ("hfuse", fuses.high), ("efuse", extended)]),
+            None => isp_table(device, "write", &[("lfuse", fuses.low), ("hfuse", fuses.high)]),
+        };
+        in_prog_mode(self.inner.clone(), device, move |inner| {
+            let _inner = inner.clone();
+            let f = match fuses.extended {
+                Some(extended) => inner.prog_fuse_ext(fuses.low, fuses.high, extended),
+                None => inner.prog_fuse(fuses.low, fuses.high),
+            };
+            let f = f.or_else(move |e| {
+                isp_fallback(_inner, e, table, ISP_WRITE_DELAY).map(|_| BytesMut::new())
+            });
+            Box::new(f)
+        })
+    }
+
+    pub fn read_lock(&self, device: &Device) -> Box<dyn Future<Item = u8, Error = StkError>> {
+        debug!("read_lock()");
+        let table = isp_table(device, "read", &[("lock", 0)]);
+        in_prog_mode(self.inner.clone(), device, move |inner| {
+            let _inner = inner.clone();
+            let f = inner.read_lock()
+                .map(|lock| lock.to_vec())
+                .or_else(move |e| isp_fallback(_inner, e, table, Duration::from_millis(0)))
+                .map(|lock| lock[0]);
+            Box::new(f)
+        })
+    }
+
+    pub fn write_lock(&self, device: &Device, lock: u8) -> ResponseFuture {
+        debug!("write_lock(0x{:02x})", lock);
+        let table = isp_table(device, "write", &[("lock", lock)]);
+        in_prog_mode(self.inner.clone(), device, move |inner| {
+            let _inner = inner.clone();
+            let f = inner.prog_lock(lock).or_else(move |e| {
+                isp_fallback(_inner, e, table, ISP_WRITE_DELAY).map(|_| BytesMut::new())
+            });
+            Box::new(f)
+        })
+    }
+
+    /// Whether the target moves the address on by itself after each read or
+    /// write. The target must be in programming mode.
+    pub fn check_autoinc(&self) -> Box<dyn Future<Item = bool, Error = StkError>> {
+        debug!("check_autoinc()");
+        check_autoinc(self.inner.clone())
+    }
+
+    /// Read `device`'s oscillator calibration byte, the value the part loads
+    /// into OSCCAL at reset.
+    pub fn read_osccal(&self, device: &Device) -> Box<dyn Future<Item = u8, Error = StkError>> {
+        debug!("read_osccal()");
+        let _device = device.clone();
+        in_prog_mode(self.inner.clone(), device, move |inner| read_osccal(inner, &_device, None))
+    }
+
+    /// Read calibration byte `index` of a part with more than one, like the
+    /// ATmega8 with one for each of its 1, 2, 4 and 8 MHz RC oscillator
+    /// settings.
+    pub fn read_osccal_ext(&self, device: &Device, index: u8) -> Box<dyn Future<Item = u8, Error = StkError>> {
+        debug!("read_osccal_ext({})", index);
+        let _device = device.clone();
+        in_prog_mode(self.inner.clone(), device, move |inner| read_osccal(inner, &_device, Some(index)))
+    }
+
+    /// Erase `device`'s flash, EEPROM and lock bits, waiting as long as the
+    /// part needs. The target must be in programming mode, and is taken out
+    /// of it and put back in afterwards.
+    pub fn chip_erase(&self, device: &Device) -> ResponseFuture {
+        debug!("chip_erase()");
+        chip_erase(self.inner.clone(), device)
+    }
+
+    /// Send a raw 4 byte ISP instruction to the target and return the last
+    /// byte it answers with. The target must be in programming mode.
+    pub fn universal(&self, instruction: [u8; 4]) -> Box<dyn Future<Item = u8, Error = StkError>> {
+        debug!("universal({:02x?})", instruction);
+        Box::new(self.inner.universal(instruction).map(|result| result[0]))
+    }
+
+    /// Send up to 256 bytes to the target over SPI in one go. Nothing is
+    /// read back. The target must be in programming mode.
+    pub fn universal_multi(&self, data: &[u8]) -> ResponseFuture {
+        debug!("universal_multi({} bytes)", data.len());
+        if data.is_empty() || data.len() > 256 {
+            return Box::new(future::err(StkError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput, "CmndStkUniversalMulti takes 1 to 256 bytes"))));
+        }
+        self.inner.universal_multi(data)
+    }
+
+    /// Read `len` bytes of `memory` from `start` with the ISP instructions
+    /// in `device`'s opcode table, one `CmndStkUniversal` per byte. This
+    /// reaches memories the STK500 has no command for, like the calibration
+    /// bytes. The target must be in programming mode. Memories the table
+    /// has no instruction for fail with `StkError::UnsupportedOperation`.
+    pub fn isp_read(&self, device: &Device, memory: &str, start: u32, len: u32)
+        -> Box<dyn Future<Item = Vec<u8>, Error = StkError>>
+    {
+        debug!("isp_read({}, {}, {})", memory, start, len);
+        let op = match device.opcode(memory, "read") {
+            Some(op) => op,
+            None => return Box::new(future::err(StkError::UnsupportedOperation {
+                memory: memory.to_string(), operation: "read",
+            })),
+        };
+        let table = (start..start + len).map(|address| (op.clone(), address, 0)).collect();
+        isp_execute(self.inner.clone(), table, Duration::from_millis(0))
+    }
+
+    /// Write `data` to `memory` from `start` with the ISP instructions in
+    /// `device`'s opcode table, like `isp_read`.
+    pub fn isp_write(&self, device: &Device, memory: &str, start: u32, data: &[u8]) -> ResponseFuture {
+        debug!("isp_write({}, {}, {} bytes)", memory, start, data.len());
+        let op = match device.opcode(memory, "write") {
+            Some(op) => op,
+            None => return Box::new(future::err(StkError::UnsupportedOperation {
+                memory: memory.to_string(), operation: "write",
+            })),
+        };
+        let table = data.iter().enumerate()
+            .map(|(i, &byte)| (op.clone(), start + i as u32, byte))
+            .collect();
+        Box::new(isp_execute(self.inner.clone(), table, ISP_WRITE_DELAY).map(|_| BytesMut::new()))
+    }
+
+    /// Program `data` into `mem_type` memory. Only pages that hold data are
+    /// written; EEPROM ones only with the bytes `data` holds.
+    pub fn prog_memory<I>(&self, mem_type: char, page_size: usize, word_size: usize, data: I)
+        -> ResponseFuture
+        where I: Into<MemoryImage>
+    {
+        self.prog_memory_with(mem_type, page_size, word_size, data, &ProgOptions::default())
+    }
+
+    /// Program the flash and EEPROM contents of an ELF file, one after the
+    /// other. The fuse and lock bytes are left alone; see `prog_elf_with`.
+    pub fn prog_elf(&self, elf: ElfImage, page_size: usize, word_size: usize) -> ResponseFuture {
+        self.prog_elf_with(elf, page_size, word_size, &ProgOptions::default())
+    }
+
+    /// Like `prog_elf`, with each programming session adjusted by `options`.
+    /// With `options.fuses` set, the fuse and lock bytes the file holds are
+    /// written last.
+    ///
+    /// `page_size` and `word_size` are the flash ones. EEPROM is byte
+    /// addressed and written in pages of `options.device`'s EEPROM page
+    /// size.
+    pub fn prog_elf_with(&self, elf: ElfImage, page_size: usize, word_size: usize, options: &ProgOptions)
+        -> ResponseFuture
+    {
+        let device = options.device.clone().unwrap_or_else(|| device::default_device().clone());
+        let fuses = match (options.fuses, elf.fuses.len()) {
+            (false, _) | (true, 0) => None,
+            (true, 1) => return Box::new(future::err(StkError::ParseElfError(".fuse section is too short"))),
+            (true, _) => Some(Fuses { low: elf.fuses[0], high: elf.fuses[1], extended: elf.fuses.get(2).cloned() }),
+        };
+        if let Some(ref fuses) = fuses {
+            if let Err(e) = fuse::check_fuses(&device, fuses) {
+                return Box::new(future::err(e));
+            }
+        }
+        let lock = if options.fuses { elf.lock } else { None };
+
+        let step0 = self.prog_memory_with('F', page_size, word_size, elf.flash, options);
+
+        let client = self.clone();
+        let eeprom = elf.eeprom;
+        let eeprom_page_size = cmp::max(device.eeprom_page_size as usize, 1);
+        // Erasing again would take the flash with it
+        let _options = ProgOptions { erase: false, ..options.clone() };
+        let step1 = move |resp| -> ResponseFuture {
+            if eeprom.is_empty() {
+                Box::new(future::ok(resp))
+            } else {
+                client.prog_memory_with('E', eeprom_page_size, 1, eeprom, &_options)
+            }
+        };
+
+        let client = self.clone();
+        let _device = device.clone();
+        let step2 = move |resp| -> ResponseFuture {
+            match fuses {
+                Some(fuses) => client.write_fuses(&_device, fuses, false),
+                None => Box::new(future::ok(resp)),
+            }
+        };
+
+        let client = self.clone();
+        let step3 = move |resp| -> ResponseFuture {
+            match lock {
+                Some(lock) => client.write_lock(&device, lock),
+                None => Box::new(future::ok(resp)),
+            }
+        };
+
+        let f = step0
+            .and_then( step1 )
+            .and_then( step2 )
+            .and_then( step3 );
+
+        Box::new(f)
+    }
+
+    /// Like `prog_memory`, with the behaviour of the programming session
+    /// adjusted by `options`.
+    pub fn prog_memory_with<I>(&self, mem_type: char, page_size: usize, word_size: usize, data: I,
+                               options: &ProgOptions)
+        -> ResponseFuture
+        where I: Into<MemoryImage>
+    {
+        let mut data = data.into();
+        let verify = options.verify;
+        let device = options.device.clone().unwrap_or_else(|| device::default_device().clone());
+        let step0 = self.resync();
+
+        let inner = self.inner.clone();
+        let settings = options.device.as_ref().map(|d| d.parameters());
+        let step1 = move |_| {
+            inner.set_device(&settings)
+        };
+
+        let inner = self.inner.clone();
+        let settings = options.device.as_ref().map(|d| d.parameters_ext());
+        let step2 = move |_| {
+            inner.set_device_ext(&settings)
+        };
+
+        let inner = self.inner.clone();
+        let progress = options.progress.clone();
+        let step3 = move |_| {
+            inner.enter_prog_mode().map(move |_| report(&progress, Progress::Connected))
+        };
        
         let inner = self.inner.clone();
+        let expected_signature = options.signature.or(options.device.as_ref().map(|d| d.signature));
+        let expected_part = options.device.clone().filter(|d| Some(d.signature) == expected_signature);
+        let erase = if options.erase { Some(device.clone()) } else { None };
+        let progress = options.progress.clone();
+        let cancel = options.cancel.clone();
         let step4 = move |_| {
-            inner.read_sign()
+            let _inner = inner.clone();
+            inner.read_sign().and_then(move |signature| -> Box<dyn Future<Item = (), Error = StkError>> {
+                let mut sig = [0; 3];
+                sig.copy_from_slice(&signature[..3]);
+                report(&progress, Progress::SignatureRead(sig));
+                let check = match expected_part {
+                    Some(ref device) => Some(check_device_signature(device, &signature)),
+                    None => expected_signature.map(|expected| check_signature(expected, &signature)),
+                };
+                match check {
+                    Some(Err(e)) => {
+                        // Don't leave the target stuck in programming mode
+                        Box::new(_inner.leave_prog_mode().then(move |_| Err(e)))
+                    }
+                    _ if is_cancelled(&cancel) => leave_cancelled(_inner),
+                    _ => match erase {
+                        Some(device) => Box::new(chip_erase(_inner, &device).map(|_| ())),
+                        None => Box::new(future::ok(())),
+                    },
+                }
+            })
         };
 
         let inner = self.inner.clone();
-        let step5 = move |_| {
-            fn check_program(buf: &Vec<u8>) -> bool {
-                buf.iter().any(|&x| {
-                    x != 0xff
-                })
+        let osccal_address = if mem_type == 'E' { options.osccal_address } else { None };
+        let autoinc = options.autoinc;
+        let cancel = options.cancel.clone();
+        let step5 = move |_| -> Box<dyn Future<Item = (Option<u8>, bool), Error = StkError>> {
+            if is_cancelled(&cancel) {
+                return Box::new(leave_cancelled(inner).map(|_| (None, false)));
             }
-            
-            loop_fn(0 as usize, move |mut index| {
-                let mut end = index + page_size;
-                if end >= data.len() {
-                    end = data.len();
+            let _inner = inner.clone();
+            let osccal: Box<dyn Future<Item = Option<u8>, Error = StkError>> = match osccal_address {
+                Some(_) => Box::new(read_osccal(inner, &device, None).map(Some)),
+                None => Box::new(future::ok(None)),
+            };
+            let f = osccal.and_then(move |osccal| -> Box<dyn Future<Item = (Option<u8>, bool), Error = StkError>> {
+                if autoinc {
+                    Box::new(check_autoinc(_inner).map(move |autoinc| (osccal, autoinc)))
+                } else {
+                    Box::new(future::ok((osccal, false)))
                 }
-                let mut page = &data[index..end];
-                let _data = data.clone();
-                let __data = data.clone();
-                while check_program(&page.to_vec()) == false {
-                    index = end;
-                    if index > data.len() {
-                        break;
-                    }
-                    end = index + page_size;
-                    if end >= data.len() {
-                        end = data.len();
-                    }
-                    page = &data[index..end];
+            });
+            Box::new(f)
+        };
+
+        let inner = self.inner.clone();
+        let progress = options.progress.clone();
+        let cancel = options.cancel.clone();
+        let page_delay = self.inner.config.page_delay;
+        let step6 = move |(osccal, autoinc): (Option<u8>, bool)| {
+            if let (Some(address), Some(osccal)) = (osccal_address, osccal) {
+                // Written in a run of its own unless next to other data, so
+                // the cells around it keep their contents
+                data.insert(address, &[osccal]);
+            }
+            // Padding an EEPROM page would overwrite the cells in its gaps
+            let pages = if mem_type == 'E' { data.runs(page_size) } else { data.pages(page_size) };
+            // Bytes written once each page is done
+            let written: Vec<usize> = pages.iter()
+                .scan(0, |bytes, (_, page)| {
+                    *bytes += page.len();
+                    Some(*bytes)
+                })
+                .collect();
+            let total = written.last().cloned().unwrap_or(0);
+            let cancelled_inner = inner.clone();
+            loop_fn((0, None), move |(index, next_address): PageState|
+                -> Box<dyn Future<Item = Loop<(), PageState>, Error = StkError>>
+            {
+                if index >= pages.len() {
+                    return Box::new(future::ok(Loop::Break(())));
+                }
+                if is_cancelled(&cancel) {
+                    return Box::new(future::err(StkError::Cancelled));
                 }
+                let (address, ref page) = pages[index];
+                let address = address as usize;
+                let page = page.clone();
+                let page_end = address + page.len();
+                let bytes = written[index];
+                let progress = progress.clone();
                 let _inner = inner.clone();
                 let __inner = inner.clone();
-                futures_timer::Delay::new(Duration::from_millis(50))
-                    .map_err(|_|{io::Error::new(io::ErrorKind::Other, "Timeout")})
-                    .and_then( move |_| {
-                    _inner.load_address((index / word_size) as u16)
-                }).and_then(move |_| {
-                    let ref page = _data[index..end];
-                    __inner.prog_page(mem_type, &page.to_vec())
+                let ___inner = inner.clone();
+                let f = futures_timer::Delay::new(page_delay)
+                    .map_err(StkError::from)
+                    .and_then( move |_| -> ResponseFuture {
+                    // Pages starting a new 64K word bank need it selected
+                    if autoinc && next_address == Some(address) && (address / word_size) & 0xffff != 0 {
+                        Box::new(future::ok(BytesMut::new()))
+                    } else {
+                        load_address_ext(_inner, address, word_size)
+                    }
                 }).and_then(move |_| {
-                    if end < __data.len() {
-                        Ok(Loop::Continue(end))
+                    __inner.prog_page(mem_type, &page)
+                        .map(move |_| page)
+                }).and_then(move |page| -> Box<dyn Future<Item = Option<usize>, Error = StkError>> {
+                    report(&progress, Progress::PageWritten { address, bytes, total });
+                    if verify {
+                        // Reading the page back moves the address too
+                        let f = verify_page(___inner, mem_type, address, word_size, page).map(move |_| {
+                            report(&progress, Progress::Verified { address });
+                            None
+                        });
+                        Box::new(f)
                     } else {
-                        Ok(Loop::Break(end))
+                        Box::new(future::ok(Some(page_end)))
                     }
-                })
+                }).map(move |next_address| Loop::Continue((index + 1, next_address)));
+                Box::new(f)
+            }).or_else(move |e| -> Box<dyn Future<Item = (), Error = StkError>> {
+                match e {
+                    StkError::Cancelled => leave_cancelled(cancelled_inner),
+                    e => Box::new(future::err(e)),
+                }
             })
         };
 
-        let inner = self.inner.clone();
-        let step6 = move |_| {
-            inner.leave_prog_mode()
-        };
+        let inner = self.inner.clone();
+        let progress = options.progress.clone();
+        let step7 = move |_| {
+            inner.leave_prog_mode().map(move |resp| {
+                report(&progress, Progress::Done);
+                resp
+            })
+        };
+
+        let f = step0
+            .and_then( step1 )
+            .and_then( step2 )
+            .and_then( step3 )
+            .and_then( step4 )
+            .and_then( step5 )
+            .and_then( step6 )
+            .and_then( step7 );
+
+        Box::new(f)
+    }
+}
+
+fn resync<T>(inner: Arc<Inner<T>>) -> Box<dyn Future<Item = (), Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    resync_with(move || Box::new(inner.get_sync().map(|_| ())))
+}
+
+/// Send `CmndStkGetSync` with `get_sync` until the target has answered it
+/// `RESYNC_REPLIES` times in a row.
+fn resync_with<F>(get_sync: F) -> Box<dyn Future<Item = (), Error = StkError>>
+    where F: Fn() -> Box<dyn Future<Item = (), Error = StkError>> + 'static
+{
+    let f = loop_fn((0, 0), move |(attempt, in_sync)| {
+        get_sync().then(move |res| -> Box<dyn Future<Item = Loop<(), (u32, u32)>, Error = StkError>> {
+            match res {
+                Ok(_) if in_sync + 1 >= RESYNC_REPLIES => Box::new(future::ok(Loop::Break(()))),
+                Ok(_) => Box::new(future::ok(Loop::Continue((attempt, in_sync + 1)))),
+                // The transport itself is gone, no point in trying again
+                Err(e @ StkError::Io(_)) => Box::new(future::err(e)),
+                Err(e) => {
+                    debug!("resync attempt {} failed: {}", attempt, e);
+                    if attempt + 1 >= RESYNC_ATTEMPTS {
+                        return Box::new(future::err(e));
+                    }
+                    let f = futures_timer::Delay::new(resync_backoff(attempt))
+                        .map_err(StkError::from)
+                        .map(move |_| Loop::Continue((attempt + 1, 0)));
+                    Box::new(f)
+                }
+            }
+        })
+    });
+    Box::new(f)
+}
+
+/// Compare a signature read from the target against the expected one.
+pub fn check_signature(expected: [u8; 3], actual: &[u8]) -> Result<(), StkError> {
+    if actual == expected {
+        return Ok(());
+    }
+    let mut sig = [0; 3];
+    for (dst, src) in sig.iter_mut().zip(actual.iter()) {
+        *dst = *src;
+    }
+    Err(StkError::SignatureMismatch { expected, expected_part: None, actual: sig })
+}
+
+/// Compare a signature read from the target against `device`'s. A mismatch
+/// names `device`.
+pub fn check_device_signature(device: &Device, actual: &[u8]) -> Result<(), StkError> {
+    check_signature(device.signature, actual).map_err(|e| match e {
+        StkError::SignatureMismatch { expected, actual, .. } => StkError::SignatureMismatch {
+            expected, expected_part: Some(device.name.to_string()), actual,
+        },
+        e => e,
+    })
+}
+
+/// Read back the page at byte address `address` and compare it against `expected`.
+fn verify_page<T>(inner: Arc<Inner<T>>, mem_type: char, address: usize, word_size: usize, expected: Vec<u8>)
+    -> Box<dyn Future<Item = (), Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let _inner = inner.clone();
+    let f = load_address_ext(inner, address, word_size)
+        .and_then(move |_| {
+            _inner.read_page(mem_type, expected.len() as u16)
+                .map(move |actual| (expected, actual))
+        }).and_then(move |(expected, actual)| {
+            match first_mismatch(&expected, &actual) {
+                None => Ok(()),
+                Some(i) => Err(StkError::VerifyMismatch {
+                    address: address + i,
+                    expected: expected[i],
+                    actual: actual.get(i).cloned(),
+                }),
+            }
+        });
+    Box::new(f)
+}
+
+/// Index of the first byte where `actual` differs from `expected`, if any.
+fn first_mismatch(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    expected.iter()
+        .enumerate()
+        .position(|(i, b)| actual.get(i) != Some(b))
+}
+
+/// Run `f` with the target in programming mode, set up for `device`. The
+/// target is taken out of programming mode again whether `f` succeeds or not.
+fn in_prog_mode<T, F, R>(inner: Arc<Inner<T>>, device: &Device, f: F) -> Box<dyn Future<Item = R, Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static,
+          F: FnOnce(Arc<Inner<T>>) -> Box<dyn Future<Item = R, Error = StkError>> + 'static,
+          R: 'static
+{
+    let step0 = resync(inner.clone());
+
+    let _inner = inner.clone();
+    let settings = Some(device.parameters());
+    let step1 = move |_| {
+        _inner.set_device(&settings)
+    };
+
+    let _inner = inner.clone();
+    let settings = Some(device.parameters_ext());
+    let step2 = move |_| {
+        _inner.set_device_ext(&settings)
+    };
+
+    let _inner = inner.clone();
+    let step3 = move |_| {
+        _inner.enter_prog_mode()
+    };
+
+    let step4 = move |_| {
+        let _inner = inner.clone();
+        f(inner).then(move |result| {
+            _inner.leave_prog_mode().then(move |_| result)
+        })
+    };
+
+    let f = step0
+        .and_then( step1 )
+        .and_then( step2 )
+        .and_then( step3 )
+        .and_then( step4 );
+
+    Box::new(f)
+}
+
+/// Read the target's signature, set up as the default part.
+fn read_signature<T>(inner: Arc<Inner<T>>) -> Box<dyn Future<Item = [u8; 3], Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    in_prog_mode(inner, device::default_device(), |inner| {
+        let f = inner.read_sign().map(|sig| {
+            let mut signature = [0; 3];
+            signature.copy_from_slice(&sig[..3]);
+            signature
+        });
+        Box::new(f)
+    })
+}
+
+/// Check that the `len` bytes from `address` are all in `device`'s EEPROM.
+fn check_eeprom_range(device: &Device, address: u16, len: usize) -> Result<(), StkError> {
+    let end = address as usize + len;
+    if len > 0 && end > device.eeprom_size as usize {
+        let address = (address as usize).max(device.eeprom_size as usize);
+        return Err(StkError::AddressOutOfRange { address });
+    }
+    Ok(())
+}
+
+/// See `Client::chip_erase`. Programmers without `CmndStkChipErase` are sent
+/// the part's chip erase ISP instruction instead.
+fn chip_erase<T>(inner: Arc<Inner<T>>, device: &Device) -> ResponseFuture
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let table = isp_table(device, "chip_erase", &[("", 0)]);
+    let delay = Duration::from_micros(device.chip_erase_delay as u64);
+
+    let _inner = inner.clone();
+    let step0 = inner.chip_erase().or_else(move |e| {
+        isp_fallback(_inner, e, table, Duration::from_millis(0)).map(|_| BytesMut::new())
+    });
+
+    let step1 = move |_| {
+        futures_timer::Delay::new(delay).map_err(StkError::from)
+    };
+
+    // Many parts need programming mode entered afresh after an erase
+    let _inner = inner.clone();
+    let step2 = move |_| {
+        _inner.leave_prog_mode()
+    };
+
+    let step3 = move |_| {
+        inner.enter_prog_mode()
+    };
+
+    let f = step0
+        .and_then( step1 )
+        .and_then( step2 )
+        .and_then( step3 );
+
+    Box::new(f)
+}
+
+fn is_cancelled(cancel: &Option<CancelHandle>) -> bool {
+    cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled())
+}
+
+/// Take the target out of programming mode after a session was cancelled,
+/// resyncing first if it doesn't answer, then fail with `Cancelled`.
+fn leave_cancelled<T>(inner: Arc<Inner<T>>) -> Box<dyn Future<Item = (), Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let _inner = inner.clone();
+    let f = inner.leave_prog_mode()
+        .or_else(move |e| {
+            debug!("leave_prog_mode() failed after cancelling: {}", e);
+            let __inner = _inner.clone();
+            resync(_inner).and_then(move |_| __inner.leave_prog_mode())
+        })
+        .then(|res| {
+            if let Err(e) = res {
+                debug!("Target may still be in programming mode: {}", e);
+            }
+            Err(StkError::Cancelled)
+        });
+    Box::new(f)
+}
+
+fn check_autoinc<T>(inner: Arc<Inner<T>>) -> Box<dyn Future<Item = bool, Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let f = inner.check_autoinc().then(|res| match res {
+        Ok(_) => Ok(true),
+        Err(StkError::Failed) | Err(StkError::Unknown) => Ok(false),
+        Err(e) => Err(e),
+    });
+    Box::new(f)
+}
+
+/// Read calibration byte `index`, or the only one if `index` is `None`, with
+/// `CmndStkReadOsccalExt` or `CmndStkReadOsccal`. Programmers without them
+/// are sent the part's calibration read ISP instruction instead.
+fn read_osccal<T>(inner: Arc<Inner<T>>, device: &Device, index: Option<u8>)
+    -> Box<dyn Future<Item = u8, Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let table = device.opcode("calibration", "read")
+        .map(|op| vec![(op, index.unwrap_or(0) as u32, 0)]);
+    let _inner = inner.clone();
+    let f = match index {
+        Some(index) => inner.read_osccal_ext(index),
+        None => inner.read_osccal(),
+    };
+    let f = f.map(|osccal| osccal.to_vec())
+        .or_else(move |e| isp_fallback(_inner, e, table, Duration::from_millis(0)))
+        .map(|osccal| osccal[0]);
+    Box::new(f)
+}
 
-        let f = step0
-            .and_then( step1 )
-            .and_then( step2 )
-            .and_then( step3 )
-            .and_then( step4 )
-            .and_then( step5 )
-            .and_then( step6 );
+/// Load byte address `address`, in words of `word_size` bytes. Flash beyond
+/// 128 KiB is reached by first selecting the 64K word bank holding the
+/// address, when that differs from the last one selected.
+fn load_address_ext<T>(inner: Arc<Inner<T>>, address: usize, word_size: usize) -> ResponseFuture
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let word_address = address / word_size;
+    let bank = word_address >> 16;
+    if bank > 0xff {
+        return Box::new(future::err(StkError::AddressOutOfRange { address }));
+    }
+    let bank = bank as u8;
+    if bank == inner.bank.get() {
+        return inner.load_address(word_address as u16);
+    }
+    let _inner = inner.clone();
+    let f = inner.universal([LOAD_EXT_ADDR, 0x00, bank, 0x00])
+        .and_then(move |_| {
+            _inner.bank.set(bank);
+            _inner.load_address(word_address as u16)
+        });
+    Box::new(f)
+}
+
+/// The index of the next page to write, and the byte address the target will
+/// write it to if no address is loaded, when it increments the address by
+/// itself
+type PageState = (usize, Option<usize>);
+
+/// ISP instructions, with the address and input byte to fill into each
+type IspTable = Vec<(Opcode, u32, u8)>;
+
+/// Look up `operation` on each of `memories` in `device`'s opcode table.
+/// `None` if any of them is missing.
+fn isp_table(device: &Device, operation: &str, memories: &[(&str, u8)]) -> Option<IspTable> {
+    memories.iter()
+        .map(|&(memory, input)| device.opcode(memory, operation).map(|op| (op, 0, input)))
+        .collect()
+}
 
+/// Send each instruction in `table` with `CmndStkUniversal`, waiting `delay`
+/// after each one, and collect the bytes they read.
+fn isp_execute<T>(inner: Arc<Inner<T>>, table: IspTable, delay: Duration)
+    -> Box<dyn Future<Item = Vec<u8>, Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let f = loop_fn((0, Vec::with_capacity(table.len())), move |(index, mut results)| {
+        if index == table.len() {
+            return Box::new(future::ok(Loop::Break(results))) as Box<dyn Future<Item = _, Error = StkError>>;
+        }
+        let (ref op, address, input) = table[index];
+        let op = op.clone();
+        let f = inner.universal(op.instruction(address, input))
+            .and_then(move |result| {
+                futures_timer::Delay::new(delay)
+                    .map_err(StkError::from)
+                    .map(move |_| op.output(result[0]))
+            })
+            .map(move |result| {
+                results.push(result);
+                Loop::Continue((index + 1, results))
+            });
         Box::new(f)
+    });
+    Box::new(f)
+}
+
+/// Fall back on ISP instructions when the programmer doesn't know a
+/// dedicated command. Other errors, and a missing `table`, are passed on.
+fn isp_fallback<T>(inner: Arc<Inner<T>>, error: StkError, table: Option<IspTable>, delay: Duration)
+    -> Box<dyn Future<Item = Vec<u8>, Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    match (error, table) {
+        (StkError::Unknown, Some(table)) => isp_execute(inner, table, delay),
+        (e, _) => Box::new(future::err(e)),
     }
 }
 
+/// Read `len` bytes starting at byte address `start`, one page at a time. The
+/// target must already be in programming mode.
+fn read_pages<T>(inner: Arc<Inner<T>>, mem_type: char, start: usize, len: usize, page_size: usize, word_size: usize)
+    -> Box<dyn Future<Item = Vec<u8>, Error = StkError>>
+    where T: AsyncRead + AsyncWrite + 'static
+{
+    let end = start + len;
+    let f = loop_fn((start, Vec::with_capacity(len)), move |(index, mut buf)| {
+        let page_end = if index + page_size < end { index + page_size } else { end };
+        let _inner = inner.clone();
+        load_address_ext(inner.clone(), index, word_size)
+            .and_then(move |_| {
+                _inner.read_page(mem_type, (page_end - index) as u16)
+            }).and_then(move |page| {
+                buf.extend_from_slice(&page);
+                if page_end < end {
+                    Ok(Loop::Continue((page_end, buf)))
+                } else {
+                    Ok(Loop::Break(buf))
+                }
+            })
+    });
+    Box::new(f)
+}
+
 struct Inner<T>
     where T: AsyncRead + AsyncWrite + 'static
 {
     inner: Timeout<ClientService<T, Stk500Proto>>,
+    config: ClientConfig,
+    // The 64K word bank of flash selected on the target, 0 after entering
+    // programming mode
+    bank: Cell<u8>,
 }
 
 impl<T> Inner<T>
     where T: AsyncRead + AsyncWrite + 'static
 {
-    fn new(handle: &Handle, io_transport: T) -> Inner<T> {
-        let proto = Stk500Proto;
+    fn new(handle: &Handle, io_transport: T, config: ClientConfig) -> Inner<T> {
+        let lost = Arc::new(AtomicUsize::new(0));
+        let proto = Stk500Proto{ lost: lost.clone() };
         let inner_service = proto.bind_client(handle, io_transport);
         let service = Timeout::new(
             inner_service,
-            Duration::from_millis(500),
+            config.clone(),
+            lost,
             handle);
-        Inner{ inner: service }
+        Inner{ inner: service, config, bank: Cell::new(0) }
     }
 
     fn get_sync(&self) -> ResponseFuture {
@@ -186,27 +1229,38 @@ impl<T> Inner<T>
         self.call(packet)
     }
 
-    fn set_device(&self, payload: &Option<Vec<u8>>) -> ResponseFuture {
-        let p = match *payload{
-            Some(ref buf) => buf.clone(),
-            None => vec![0x86, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01,
-            0x03, 0xff, 0xff, 0xff, 0xff, 0x00, 0x80, 0x04, 0x00,
-            0x00, 0x00, 0x80, 0x00]
+    fn set_device(&self, parameters: &Option<DeviceParameters>) -> ResponseFuture {
+        let p = match *parameters {
+            Some(ref parameters) => parameters.to_bytes(),
+            None => device::default_device().parameters().to_bytes()
         };
         let packet = Packet{command: Command::CmndStkSetDevice, payload: p};
         self.call(packet)
     }
 
-    fn set_device_ext(&self, payload: &Option<Vec<u8>>) -> ResponseFuture {
-        let p = match *payload{
-            Some(ref buf) => buf.clone(),
-            None => vec![0x05, 0x04, 0xd7, 0xc2, 0x00]
+    fn set_device_ext(&self, parameters: &Option<DeviceParametersExt>) -> ResponseFuture {
+        let p = match *parameters {
+            Some(ref parameters) => parameters.to_bytes(),
+            None => device::default_device().parameters_ext().to_bytes()
         };
         let packet = Packet{command: Command::CmndStkSetDeviceExt, payload: p};
         self.call(packet)
     }
 
+    fn sign_on(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkGetSignOn, payload: vec![] } )
+    }
+
+    fn get_parameter(&self, parameter: Parameter) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkGetParameter, payload: vec![parameter as u8] } )
+    }
+
+    fn set_parameter(&self, parameter: Parameter, value: u8) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkSetParameter, payload: vec![parameter as u8, value] } )
+    }
+
     fn enter_prog_mode(&self) -> ResponseFuture {
+        self.bank.set(0);
         self.call(
             Packet{ command: Command::CmndStkEnterProgmode, payload: vec![] }
         )
@@ -236,9 +1290,80 @@ impl<T> Inner<T>
         self.call( Packet{ command: Command::CmndStkProgPage, payload: payload } )
     }
 
+    fn prog_flash(&self, word: u16) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkProgFlash, payload: vec![word as u8, (word >> 8) as u8] } )
+    }
+
+    fn prog_data(&self, byte: u8) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkProgData, payload: vec![byte] } )
+    }
+
+    fn read_flash(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkReadFlash, payload: vec![] } )
+    }
+
+    fn read_data(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkReadData, payload: vec![] } )
+    }
+
+    fn read_page(&self, mem_type: char, len: u16) -> ResponseFuture {
+        let payload = vec![ (len>>8) as u8, (len&0x00ff) as u8, mem_type as u8];
+        self.call( Packet{ command: Command::CmndStkReadPage, payload } )
+    }
+
     fn leave_prog_mode(&self) -> ResponseFuture {
         self.call( Packet{ command: Command::CmndStkLeaveProgmode, payload: vec![] } )
     }
+
+    fn check_autoinc(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkCheckAutoinc, payload: vec![] } )
+    }
+
+    fn chip_erase(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkChipErase, payload: vec![] } )
+    }
+
+    fn read_osccal(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkReadOsccal, payload: vec![] } )
+    }
+
+    fn read_osccal_ext(&self, index: u8) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkReadOsccalExt, payload: vec![index] } )
+    }
+
+    fn read_fuse(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkReadFuse, payload: vec![] } )
+    }
+
+    fn read_fuse_ext(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkReadFuseExt, payload: vec![] } )
+    }
+
+    fn read_lock(&self) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkReadLock, payload: vec![] } )
+    }
+
+    fn prog_fuse(&self, low: u8, high: u8) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkProgFuse, payload: vec![low, high] } )
+    }
+
+    fn prog_fuse_ext(&self, low: u8, high: u8, extended: u8) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkProgFuseExt, payload: vec![low, high, extended] } )
+    }
+
+    fn prog_lock(&self, lock: u8) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkProgLock, payload: vec![lock] } )
+    }
+
+    fn universal(&self, instruction: [u8; 4]) -> ResponseFuture {
+        self.call( Packet{ command: Command::CmndStkUniversal, payload: instruction.to_vec() } )
+    }
+
+    fn universal_multi(&self, data: &[u8]) -> ResponseFuture {
+        let mut payload = vec![(data.len() - 1) as u8];
+        payload.extend_from_slice(data);
+        self.call( Packet{ command: Command::CmndStkUniversalMulti, payload } )
+    }
 }
 
 impl<T> Service for Inner<T>
@@ -246,22 +1371,64 @@ impl<T> Service for Inner<T>
 {
     type Request = Packet;
     type Response = BytesMut;
-    type Error = io::Error;
+    type Error = StkError;
     type Future = ResponseFuture;
 
     fn call(&self, req: Packet) -> Self::Future {
-        Box::new(self.inner.call(req))
+        let f = self.inner.call(req)
+            .map_err(StkError::from)
+            .and_then(Reply::into_result);
+        Box::new(f)
+    }
+}
+
+/// A reply decoded by `Stk500Codec`.
+pub enum Reply {
+    /// RespStkInsync, any data, RespStkOk. Holds the data.
+    Ok(BytesMut),
+    /// RespStkInsync, any data, RespStkFailed
+    Failed,
+    /// RespStkInsync, any data, RespStkNodevice
+    NoDevice,
+    /// A lone RespStkNosync
+    NoSync,
+    /// A lone RespStkUnknown
+    Unknown,
+    /// Stands in for the reply to a command that timed out and never got one
+    Stale,
+}
+
+impl Reply {
+    fn into_result(self) -> Result<BytesMut, StkError> {
+        match self {
+            Reply::Ok(data) => Ok(data),
+            Reply::Failed => Err(StkError::Failed),
+            Reply::NoDevice => Err(StkError::NoDevice),
+            Reply::NoSync => Err(StkError::OutOfSync),
+            Reply::Unknown => Err(StkError::Unknown),
+            Reply::Stale => Err(StkError::Timeout),
+        }
     }
 }
 
 pub struct Stk500Codec {
-    last_command: Command,
-    expected_response_len: usize
+    // Reply lengths of the commands sent but not answered yet, oldest first.
+    // Replies come back in order, so a late reply to a command that timed
+    // out is decoded at its own length and goes to that command.
+    expected: VecDeque<usize>,
+    // How many of the oldest commands in `expected` `Timeout` gave up on
+    // ever getting a reply to
+    lost: Arc<AtomicUsize>,
 }
 
 impl Stk500Codec {
-    fn new() -> Stk500Codec {
-        Stk500Codec{last_command: Command::CmndStkGetSync, expected_response_len: 2}
+    fn new(lost: Arc<AtomicUsize>) -> Stk500Codec {
+        Stk500Codec{ expected: VecDeque::new(), lost }
+    }
+
+    fn reply(&mut self, reply: Reply) -> Result<Option<Reply>, io::Error> {
+        self.expected.pop_front();
+        Ok(Some(reply))
     }
 }
 
@@ -274,8 +1441,7 @@ impl Encoder for Stk500Codec {
         item: Self::Item, 
         dst: &mut BytesMut
     ) -> Result<(), Self::Error> {
-        self.last_command = item.command;
-        self.expected_response_len = expected_response_len(&item);
+        self.expected.push_back(expected_response_len(&item));
         dst.extend([item.command as u8].iter());
         dst.extend_from_slice(&item.payload.as_slice());
         dst.extend([Command::SyncCrcEop as u8].iter());
@@ -283,6 +1449,20 @@ impl Encoder for Stk500Codec {
     }
 }
 
+/// Whether sending `command` twice leaves the target as sending it once
+/// does. Reads of flash and EEPROM move the address on when the target
+/// auto-increments, so they aren't.
+pub fn is_idempotent(command: Command) -> bool {
+    matches!(command,
+        Command::CmndStkGetSync | Command::CmndStkGetSignOn
+        | Command::CmndStkSetParameter | Command::CmndStkGetParameter
+        | Command::CmndStkSetDevice | Command::CmndStkSetDeviceExt
+        | Command::CmndStkEnterProgmode | Command::CmndStkLeaveProgmode
+        | Command::CmndStkCheckAutoinc | Command::CmndStkLoadAddress
+        | Command::CmndStkReadFuse | Command::CmndStkReadFuseExt | Command::CmndStkReadLock
+        | Command::CmndStkReadSign | Command::CmndStkReadOsccal | Command::CmndStkReadOsccalExt)
+}
+
 fn expected_response_len(packet: &Packet) -> usize {
     let command = packet.command;
     match command {
@@ -296,8 +1476,9 @@ fn expected_response_len(packet: &Packet) -> usize {
         Command::CmndStkReadPage => {
             let bytes_high = packet.payload[0];
             let bytes_low = packet.payload[1];
-            let bytes_len:usize = (bytes_high as usize) << 8 + (bytes_low as usize);
-            bytes_len
+            // RespStkInsync, the page data, then RespStkOk
+            let bytes_len = ((bytes_high as usize) << 8) | (bytes_low as usize);
+            bytes_len + 2
         }
         Command::CmndStkReadSign => 5,
         Command::CmndStkReadOsccal => 3,
@@ -308,7 +1489,7 @@ fn expected_response_len(packet: &Packet) -> usize {
 }
 
 impl Decoder for Stk500Codec {
-    type Item = BytesMut;
+    type Item = Reply;
     type Error = io::Error;
 
     fn decode(
@@ -316,36 +1497,94 @@ impl Decoder for Stk500Codec {
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
 
-        if src.len() < self.expected_response_len {
-            Ok(None)
-        } 
-        else if src[0] != Command::RespStkInsync as u8 {
-            Err(io::Error::new(io::ErrorKind::Other, "Stk500 Response Error: Expected first byte to be RespStkInsync"))
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        // Complete the commands whose reply is never coming, so that what has
+        // arrived goes to the command that is waiting for it.
+        if !self.expected.is_empty() && self.lost.load(Ordering::SeqCst) > 0 {
+            self.lost.fetch_sub(1, Ordering::SeqCst);
+            return self.reply(Reply::Stale);
         }
-        else if src[self.expected_response_len-1] != Command::RespStkOk as u8 {
-            Err(io::Error::new(io::ErrorKind::Other, "Stk500 Response Error: Expected last byte to be RespStkOk"))
-        } 
-        else {
-            let mut resp = src.split_to(self.expected_response_len);
-            resp.split_to(1); // Get rid of the first byte
-            let len = resp.len();
-            resp.truncate( len - 1 );
-            Ok(Some(resp))
+
+        let expected_len = match self.expected.front() {
+            Some(&len) => len,
+            None => {
+                debug!("Discarding {} bytes received while no command is pending", src.len());
+                src.clear();
+                return Ok(None);
+            }
+        };
+
+        loop {
+            // Single byte replies, sent without a preceding RespStkInsync
+            if src[0] == Command::RespStkNosync as u8 {
+                src.split_to(1);
+                return self.reply(Reply::NoSync);
+            }
+            if src[0] == Command::RespStkUnknown as u8 {
+                src.split_to(1);
+                return self.reply(Reply::Unknown);
+            }
+
+            if src[0] != Command::RespStkInsync as u8 {
+                // Line noise or leftover output from the target. Skip ahead to
+                // the next frame boundary.
+                let skip = src.iter()
+                    .position(|&b| b == Command::RespStkInsync as u8)
+                    .unwrap_or(src.len());
+                debug!("Discarding {} bytes of garbage", skip);
+                src.split_to(skip);
+                if src.is_empty() {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            if src.len() < expected_len {
+                return Ok(None);
+            }
+
+            let trailer = src[expected_len-1];
+            let reply = if trailer == Command::RespStkOk as u8 {
+                let mut resp = src.split_to(expected_len);
+                resp.split_to(1); // Get rid of the first byte
+                let len = resp.len();
+                resp.truncate( len - 1 );
+                Reply::Ok(resp)
+            } else if trailer == Command::RespStkFailed as u8 {
+                src.split_to(expected_len);
+                Reply::Failed
+            } else if trailer == Command::RespStkNodevice as u8 {
+                src.split_to(expected_len);
+                Reply::NoDevice
+            } else {
+                // That RespStkInsync was part of the garbage
+                src.split_to(1);
+                if src.is_empty() {
+                    return Ok(None);
+                }
+                continue;
+            };
+            return self.reply(reply);
         }
-        
     }
 }
 
-struct Stk500Proto;
+struct Stk500Proto {
+    // Shared by the codec and `Timeout`
+    lost: Arc<AtomicUsize>,
+}
 
 impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for Stk500Proto {
     type Request = Packet;
-    type Response = BytesMut;
+    type Response = Reply;
     type Transport = Framed<T, Stk500Codec>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(Stk500Codec::new()))
+        Ok(io.framed(Stk500Codec::new(self.lost.clone())))
     }
 }
 
@@ -356,51 +1595,752 @@ pub fn enter_prog_mode() -> Packet {
 }
 */
 
+/// Applies the timeouts and retry policy of a `ClientConfig` to each command.
 struct Timeout<T> {
-    delay: Duration,
-    upstream: T,
+    config: ClientConfig,
+    upstream: Arc<T>,
+    // Replies given up on, shared with the codec
+    lost: Arc<AtomicUsize>,
     handle: Handle,
 }
 
 impl<T> Timeout<T> {
-    fn new(upstream: T, delay: Duration, handle: &Handle) -> Timeout<T> {
+    fn new(upstream: T, config: ClientConfig, lost: Arc<AtomicUsize>, handle: &Handle) -> Timeout<T> {
         Timeout{
-            delay: delay,
-            upstream: upstream,
+            config,
+            upstream: Arc::new(upstream),
+            lost,
             handle: handle.clone()
         }
     }
 }
 
+/// Send `req`, failing with `StkError::Timeout` if no reply comes within
+/// `delay`.
+///
+/// The reply may only be late, so it is waited for as long again before
+/// failing. Nothing else is sent meanwhile, and a reply arriving then is
+/// dropped. If none arrives, the codec is told it never will.
+fn call_with_timeout<T>(upstream: &T, req: Packet, delay: Option<Duration>, lost: &Arc<AtomicUsize>,
+                        handle: &Handle)
+    -> Box<dyn Future<Item = Reply, Error = io::Error>>
+    where T: Service<Request = Packet, Response = Reply, Error = io::Error>,
+          T::Future: 'static,
+{
+    let delay = match delay {
+        Some(delay) => delay,
+        None => return Box::new(upstream.call(req)),
+    };
+    let timeout = tokio_core::reactor::Timeout::new(delay, handle).unwrap();
+    let lost = lost.clone();
+    let handle = handle.clone();
+
+    let work = upstream.call(req).select2(timeout).then(move |res| -> Box<dyn Future<Item = Reply, Error = io::Error>> {
+        match res {
+            Ok(Either::A((item, _timeout))) => Box::new(future::ok(item)),
+            Ok(Either::B((_timeout_error, item))) => {
+                debug!("Timed out, draining");
+                let drain = tokio_core::reactor::Timeout::new(delay, &handle).unwrap();
+                let f = item.select2(drain).then(move |res| {
+                    match res {
+                        Ok(Either::A(_)) => debug!("Dropped a late reply"),
+                        Err(Either::A(_)) => {}
+                        _ => { lost.fetch_add(1, Ordering::SeqCst); }
+                    }
+                    Err(StkError::Timeout.into())
+                });
+                Box::new(f)
+            }
+            Err(Either::A((item_error, _timeout))) => {
+                Box::new(future::err(item_error))
+            }
+            Err(Either::B((timeout_error, _item))) => Box::new(future::err(timeout_error))
+        }
+    });
+
+    Box::new(work)
+}
+
 impl<T> Service for Timeout<T> 
-    where T: Service,
-          T::Error: From<io::Error> + 'static,
-          T::Request: 'static,
-          T::Response: 'static,
+    where T: Service<Request = Packet, Response = Reply, Error = io::Error> + 'static,
           T::Future: 'static,
 {
-    type Request = T::Request;
-    type Response = T::Response;
-    type Error = T::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Request = Packet;
+    type Response = Reply;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        let timeout = tokio_core::reactor::Timeout::new(self.delay, &self.handle).unwrap();
+        let upstream = self.upstream.clone();
+        let lost = self.lost.clone();
+        let handle = self.handle.clone();
+        let command = req.command;
+        let delay = self.config.timeout_for(command);
+        let sync_delay = self.config.timeout_for(Command::CmndStkGetSync);
+        let retry = self.config.retry.clone();
 
-        let work = self.upstream.call(req).select2(timeout).then(|res| {
-            match res {
-                Ok(Either::A((item, _timeout))) => Ok(item),
-                Ok(Either::B((_timeout_error, _item))) => {
-                    Err(Self::Error::from(io::Error::new( io::ErrorKind::Other, "Timeout.")))
+        let work = loop_fn(1, move |attempt| {
+            let retry = retry.clone();
+            let (_upstream, _lost, _handle) = (upstream.clone(), lost.clone(), handle.clone());
+            call_with_timeout(&*upstream, req.clone(), delay, &lost, &handle).then(move |res|
+                -> Box<dyn Future<Item = Loop<Reply, u32>, Error = io::Error>>
+            {
+                let error = match res.map_err(StkError::from).and_then(Reply::into_result) {
+                    Ok(data) => return Box::new(future::ok(Loop::Break(Reply::Ok(data)))),
+                    Err(e) => e,
+                };
+                if attempt >= retry.max_attempts || !(retry.retryable)(command, &error) {
+                    return Box::new(future::err(error.into()));
+                }
+                debug!("Attempt {} failed, retrying: {}", attempt, error);
+                let backoff = retry.backoff * (1 << (attempt - 1).min(16));
+                // Get back in step with the target before sending it again
+                let get_sync = move || -> Box<dyn Future<Item = (), Error = StkError>> {
+                    let req = Packet{ command: Command::CmndStkGetSync, payload: vec![] };
+                    let f = call_with_timeout(&*_upstream, req, sync_delay, &_lost, &_handle)
+                        .map_err(StkError::from)
+                        .and_then(Reply::into_result)
+                        .map(|_| ());
+                    Box::new(f)
+                };
+                let f = futures_timer::Delay::new(backoff)
+                    .map_err(StkError::from)
+                    .and_then(move |_| resync_with(get_sync))
+                    .map_err(io::Error::from)
+                    .map(move |_| Loop::Continue(attempt + 1));
+                Box::new(f)
+            })
+        });
+
+        Box::new(work)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::{Async, Future, Poll};
+    use futures::task::{self, Task};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::rc::Rc;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use super::{first_mismatch, Client, ClientConfig, Identity, Packet, ProgOptions, Reply, RetryPolicy, Stk500Codec};
+    use super::super::{device, Command, ElfImage, MemoryImage, StkError, Version};
+    use super::super::cancel::CancelHandle;
+    use super::super::progress::{Progress, ProgressCallback};
+    use super::super::tokio_core::reactor::Core;
+    use super::super::tokio_io::{AsyncRead, AsyncWrite};
+    use super::super::tokio_io::codec::{Encoder, Decoder};
+
+    /// A bootloader for `Client` to talk to. It keeps its flash and EEPROM
+    /// contents and records every command it is sent.
+    struct Target {
+        /// The commands received, without their SyncCrcEop
+        sent: Vec<Vec<u8>>,
+        /// Whether the address moves on after each page
+        autoinc: bool,
+        /// Commands to leave unanswered, once each
+        ignore: Vec<u8>,
+        flash: Vec<u8>,
+        eeprom: Vec<u8>,
+        osccal: u8,
+        // Word address for flash, byte address for EEPROM
+        address: usize,
+        bank: usize,
+        input: Vec<u8>,
+        output: VecDeque<u8>,
+        reader: Option<Task>,
+    }
+
+    impl Target {
+        fn new() -> Rc<RefCell<Target>> {
+            Rc::new(RefCell::new(Target {
+                sent: Vec::new(),
+                autoinc: false,
+                ignore: Vec::new(),
+                flash: vec![0xff; 0x40000],
+                eeprom: vec![0xff; 0x1000],
+                osccal: 0x9a,
+                address: 0,
+                bank: 0,
+                input: Vec::new(),
+                output: VecDeque::new(),
+                reader: None,
+            }))
+        }
+
+        /// The command byte of each command received
+        fn commands(&self) -> Vec<u8> {
+            self.sent.iter().map(|command| command[0]).collect()
+        }
+
+        /// Answer every complete command in `input`.
+        fn receive(&mut self) {
+            loop {
+                let len = match self.input.first() {
+                    Some(&0x40) => 4,
+                    Some(&0x41) | Some(&0x61) | Some(&0x63) | Some(&0x78) => 3,
+                    Some(&0x42) => 22,
+                    Some(&0x45) => 7,
+                    Some(&0x55) | Some(&0x60) | Some(&0x62) => 4,
+                    Some(&0x56) => 6,
+                    Some(&0x64) if self.input.len() >= 3 => 5 + ((self.input[1] as usize) << 8 | self.input[2] as usize),
+                    Some(&0x64) => return,
+                    Some(&0x65) | Some(&0x74) => 5,
+                    Some(_) => 2,
+                    None => return,
+                };
+                if self.input.len() < len {
+                    return;
+                }
+                let command: Vec<u8> = self.input.drain(..len).collect();
+                let command = command[..len - 1].to_vec();
+                let reply = self.execute(&command);
+                self.sent.push(command);
+                if let Some(reply) = reply {
+                    self.output.extend(reply);
+                }
+            }
+        }
+
+        fn execute(&mut self, command: &[u8]) -> Option<Vec<u8>> {
+            if let Some(i) = self.ignore.iter().position(|&c| c == command[0]) {
+                self.ignore.remove(i);
+                return None;
+            }
+            let mut data = Vec::new();
+            match command[0] {
+                0x31 => data.extend_from_slice(b"AVR STK"),
+                // Hardware version 2, firmware 1.18
+                0x41 => data.push(match command[1] {
+                    0x80 => 2,
+                    0x81 => 1,
+                    0x82 => 18,
+                    _ => 0,
+                }),
+                0x50 => self.bank = 0,
+                0x52 => {
+                    self.flash.iter_mut().for_each(|b| *b = 0xff);
+                    self.eeprom.iter_mut().for_each(|b| *b = 0xff);
+                }
+                0x53 if !self.autoinc => return Some(vec![0x12]),
+                0x55 => self.address = command[1] as usize | (command[2] as usize) << 8,
+                0x56 => {
+                    if command[1] == 0x4d {
+                        self.bank = command[3] as usize;
+                    }
+                    data.push(0);
+                }
+                0x64 | 0x74 => {
+                    let len = (command[1] as usize) << 8 | command[2] as usize;
+                    let (memory, start, step) = match command[3] {
+                        b'F' => (&mut self.flash, (self.bank << 16 | self.address) * 2, len / 2),
+                        _ => (&mut self.eeprom, self.address, len),
+                    };
+                    if command[0] == 0x64 {
+                        memory[start..start + len].copy_from_slice(&command[4..]);
+                    } else {
+                        data.extend_from_slice(&memory[start..start + len]);
+                    }
+                    if self.autoinc {
+                        self.address += step;
+                    }
                 }
-                Err(Either::A((item_error, _timeout))) => {
-                    Err(item_error)
+                0x75 => data.extend_from_slice(&[0x1e, 0x95, 0x0f]),
+                0x76 => data.push(self.osccal),
+                _ => {}
+            }
+            let mut reply = vec![0x14];
+            reply.extend(data);
+            reply.push(0x10);
+            Some(reply)
+        }
+    }
+
+    struct Transport(Rc<RefCell<Target>>);
+
+    impl io::Read for Transport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut target = self.0.borrow_mut();
+            if target.output.is_empty() {
+                target.reader = Some(task::current());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(target.output.len());
+            for (dst, src) in buf.iter_mut().zip(target.output.drain(..len)) {
+                *dst = src;
+            }
+            Ok(len)
+        }
+    }
+
+    impl io::Write for Transport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut target = self.0.borrow_mut();
+            target.input.extend_from_slice(buf);
+            target.receive();
+            if !target.output.is_empty() {
+                if let Some(reader) = target.reader.take() {
+                    reader.notify();
                 }
-                Err(Either::B((timeout_error, _item))) => Err(Self::Error::from(timeout_error))
             }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Transport {}
+
+    impl AsyncWrite for Transport {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Run `session` against `target`, without delays between pages.
+    fn run<F, R>(target: &Rc<RefCell<Target>>, session: F) -> Result<R, StkError>
+        where F: FnOnce(Client<Transport>) -> Box<dyn Future<Item = R, Error = StkError>>
+    {
+        let config = ClientConfig { page_delay: Duration::from_millis(0), ..ClientConfig::default() };
+        run_with(target, config, session)
+    }
+
+    fn run_with<F, R>(target: &Rc<RefCell<Target>>, config: ClientConfig, session: F) -> Result<R, StkError>
+        where F: FnOnce(Client<Transport>) -> Box<dyn Future<Item = R, Error = StkError>>
+    {
+        let mut core = Core::new().unwrap();
+        let client = Client::with_config(&core.handle(), Transport(target.clone()), config);
+        core.run(session(client))
+    }
+
+    #[test]
+    fn read_page_frame_len() {
+        let mut codec = Stk500Codec::new(Default::default());
+        let mut out = BytesMut::new();
+        let packet = Packet{ command: Command::CmndStkReadPage, payload: vec![0x01, 0x00, b'F'] };
+        codec.encode(packet, &mut out).unwrap();
+
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[Command::RespStkInsync as u8]);
+        src.extend_from_slice(&[0xaa; 0x100]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&[Command::RespStkOk as u8]);
+        match codec.decode(&mut src).unwrap() {
+            Some(Reply::Ok(page)) => assert_eq!(page.len(), 0x100),
+            _ => panic!("Expected a page of data"),
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_error_replies() {
+        let mut codec = Stk500Codec::new(Default::default());
+        let mut out = BytesMut::new();
+        let mut src = BytesMut::new();
+        let mut next = |codec: &mut Stk500Codec, src: &mut BytesMut| {
+            let packet = Packet{ command: Command::CmndStkEnterProgmode, payload: vec![] };
+            codec.encode(packet, &mut out).unwrap();
+            codec.decode(src).unwrap()
+        };
+        src.extend_from_slice(&[
+            Command::RespStkNosync as u8,
+            Command::RespStkUnknown as u8,
+            Command::RespStkInsync as u8, Command::RespStkNodevice as u8,
+            Command::RespStkInsync as u8, Command::RespStkFailed as u8,
+            Command::RespStkInsync as u8, Command::RespStkOk as u8,
+        ]);
+        match next(&mut codec, &mut src) { Some(Reply::NoSync) => {}, _ => panic!() }
+        match next(&mut codec, &mut src) { Some(Reply::Unknown) => {}, _ => panic!() }
+        match next(&mut codec, &mut src) { Some(Reply::NoDevice) => {}, _ => panic!() }
+        match next(&mut codec, &mut src) { Some(Reply::Failed) => {}, _ => panic!() }
+        match next(&mut codec, &mut src) { Some(Reply::Ok(ref data)) if data.is_empty() => {}, _ => panic!() }
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn identity_report() {
+        let identity = Identity {
+            sign_on: "AVR STK".to_string(),
+            hardware_version: 2,
+            firmware_version: Version { major: 1, minor: 18 },
+            signature: [0x1e, 0x95, 0x0f],
+        };
+        assert_eq!(identity.to_string(), "AVR STK (hardware 2, firmware 1.18), target 1e950f (ATmega328P)");
+    }
+
+    #[test]
+    fn identify_target() {
+        let target = Target::new();
+        let identity = run(&target, |client| client.identify()).unwrap();
+        assert_eq!(identity, Identity {
+            sign_on: "AVR STK".to_string(),
+            hardware_version: 2,
+            firmware_version: Version { major: 1, minor: 18 },
+            signature: [0x1e, 0x95, 0x0f],
         });
+        assert_eq!(target.borrow().commands().last(), Some(&0x51));
+    }
 
-        Box::new(work)
+    #[test]
+    fn verify_mismatch_index() {
+        assert_eq!(first_mismatch(&[1, 2, 3], &[1, 2, 3]), None);
+        assert_eq!(first_mismatch(&[1, 2, 3], &[1, 0, 3]), Some(1));
+        assert_eq!(first_mismatch(&[1, 2, 3], &[1, 2]), Some(2));
+    }
+
+    #[test]
+    fn decode_skips_garbage() {
+        let mut codec = Stk500Codec::new(Default::default());
+        let mut out = BytesMut::new();
+        let packet = Packet{ command: Command::CmndStkReadSign, payload: vec![] };
+        codec.encode(packet, &mut out).unwrap();
+
+        let mut src = BytesMut::new();
+        src.extend_from_slice(b"Hello\r\n\x14\x00");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&[0x00, 0x14, 0x1e, 0x95, 0x0f, 0x10]);
+        match codec.decode(&mut src).unwrap() {
+            Some(Reply::Ok(ref sig)) if sig[..] == [0x1e, 0x95, 0x0f] => {}
+            _ => panic!("Expected the signature"),
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_completes_timed_out_commands() {
+        let mut codec = Stk500Codec::new(Default::default());
+        let mut out = BytesMut::new();
+        for _ in 0..2 {
+            let packet = Packet{ command: Command::CmndStkGetSync, payload: vec![] };
+            codec.encode(packet, &mut out).unwrap();
+        }
+        // The first one's reply was given up on
+        codec.lost.store(1, Ordering::SeqCst);
+
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[Command::RespStkInsync as u8, Command::RespStkOk as u8]);
+        match codec.decode(&mut src).unwrap() { Some(Reply::Stale) => {}, _ => panic!() }
+        match codec.decode(&mut src).unwrap() { Some(Reply::Ok(_)) => {}, _ => panic!() }
+        assert_eq!(codec.lost.load(Ordering::SeqCst), 0);
+
+        // Nobody is waiting for these
+        src.extend_from_slice(&[Command::RespStkInsync as u8, Command::RespStkOk as u8]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_late_reply() {
+        let mut codec = Stk500Codec::new(Default::default());
+        let mut out = BytesMut::new();
+        // The signature read timed out, then a sync was sent
+        codec.encode(Packet{ command: Command::CmndStkReadSign, payload: vec![] }, &mut out).unwrap();
+        codec.encode(Packet{ command: Command::CmndStkGetSync, payload: vec![] }, &mut out).unwrap();
+
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[0x14, 0x1e, 0x95, 0x0f, 0x10, 0x14, 0x10]);
+        match codec.decode(&mut src).unwrap() {
+            Some(Reply::Ok(ref sig)) if sig[..] == [0x1e, 0x95, 0x0f] => {}
+            _ => panic!("Expected the late signature"),
+        }
+        match codec.decode(&mut src).unwrap() {
+            Some(Reply::Ok(ref data)) if data.is_empty() => {}
+            _ => panic!("Expected the sync reply"),
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn client_config_timeouts() {
+        let mut config = ClientConfig::default();
+        config.command_timeouts.push((Command::CmndStkChipErase, Some(Duration::from_secs(5))));
+        config.command_timeouts.push((Command::CmndStkProgPage, None));
+        assert_eq!(config.timeout_for(Command::CmndStkGetSync), Some(Duration::from_millis(500)));
+        assert_eq!(config.timeout_for(Command::CmndStkChipErase), Some(Duration::from_secs(5)));
+        assert_eq!(config.timeout_for(Command::CmndStkProgPage), None);
+
+        assert_eq!(config.retry.max_attempts, 1);
+        assert!((config.retry.retryable)(Command::CmndStkReadSign, &StkError::Timeout));
+        assert!(!(config.retry.retryable)(Command::CmndStkReadSign, &StkError::Failed));
+        assert!(!(config.retry.retryable)(Command::CmndStkProgPage, &StkError::Timeout));
+        assert!(!(config.retry.retryable)(Command::CmndStkChipErase, &StkError::OutOfSync));
+    }
+
+    #[test]
+    fn prog_memory_beyond_128k() {
+        let target = Target::new();
+        let mut image = MemoryImage::new();
+        image.insert(0x100, &[1, 2]);
+        image.insert(0x3f000, &[3, 4]);
+        run(&target, |client| client.prog_memory('F', 256, 2, image)).unwrap();
+
+        let target = target.borrow();
+        assert_eq!(target.flash[0x100..0x102], [1, 2]);
+        assert_eq!(target.flash[0x3f000..0x3f002], [3, 4]);
+        assert_eq!(target.flash[0x1f000], 0xff);
+        let bank = target.sent.iter().position(|command| command[..] == [0x56, 0x4d, 0x00, 0x01, 0x00]).unwrap();
+        assert_eq!(target.sent[bank + 1], [0x55, 0x00, 0xf8]);
+    }
+
+    #[test]
+    fn prog_elf_fuses() {
+        let elf = || ElfImage {
+            flash: vec![1, 2, 3, 4].into(),
+            eeprom: MemoryImage::new(),
+            fuses: vec![0xff, 0xde, 0xfd],
+            lock: Some(0xcf),
+        };
+        let options = ProgOptions { fuses: true, ..ProgOptions::default() };
+
+        let target = Target::new();
+        run(&target, |client| client.prog_elf(elf(), 128, 2)).unwrap();
+        assert!(!target.borrow().sent.iter().any(|command| command[0] == 0x65 || command[0] == 0x63));
+
+        let target = Target::new();
+        run(&target, |client| client.prog_elf_with(elf(), 128, 2, &options)).unwrap();
+        let target = target.borrow();
+        assert_eq!(target.flash[..4], [1, 2, 3, 4]);
+        let fuses = target.sent.iter().position(|command| command[..] == [0x65, 0xff, 0xde, 0xfd]).unwrap();
+        let lock = target.sent.iter().position(|command| command[..] == [0x63, 0xcf]).unwrap();
+        assert!(fuses < lock);
+
+        // SPIEN unprogrammed
+        let target = Target::new();
+        let elf = ElfImage { fuses: vec![0xff, 0xff, 0xfd], ..elf() };
+        match run(&target, |client| client.prog_elf_with(elf, 128, 2, &options)) {
+            Err(StkError::UnsafeFuses { .. }) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert!(target.borrow().sent.is_empty());
+    }
+
+    #[test]
+    fn isp_unsupported_operation() {
+        let target = Target::new();
+        let m328p = device::by_name("m328p").unwrap();
+        match run(&target, |client| client.isp_read(m328p, "usersig", 0, 1)) {
+            Err(StkError::UnsupportedOperation { ref memory, operation: "read" }) if memory == "usersig" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match run(&target, |client| client.isp_write(m328p, "usersig", 0, &[0])) {
+            Err(StkError::UnsupportedOperation { ref memory, operation: "write" }) if memory == "usersig" => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert!(target.borrow().sent.is_empty());
+    }
+
+    #[test]
+    fn prog_memory_names_expected_part() {
+        let target = Target::new();
+        let mut t85 = device::by_name("m8").unwrap().clone();
+        t85.name = "ATtiny85".into();
+        t85.signature = [0x1e, 0x93, 0x0b];
+        let options = ProgOptions { device: Some(t85), ..ProgOptions::default() };
+        match run(&target, |client| client.prog_memory_with('F', 64, 2, vec![1, 2], &options)) {
+            Err(StkError::SignatureMismatch { expected_part: Some(ref name), .. }) if name == "ATtiny85" => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert_eq!(target.borrow().sent.last().unwrap()[..], [0x51]);
+    }
+
+    #[test]
+    fn prog_memory_erase_reenters_prog_mode() {
+        let target = Target::new();
+        target.borrow_mut().flash[0x200] = 0;
+        let options = ProgOptions { erase: true, ..ProgOptions::default() };
+        run(&target, |client| client.prog_memory_with('F', 128, 2, vec![1, 2], &options)).unwrap();
+
+        let target = target.borrow();
+        assert_eq!(target.flash[..3], [1, 2, 0xff]);
+        assert_eq!(target.flash[0x200], 0xff);
+        let commands = target.commands();
+        let erase = commands.iter().position(|&c| c == 0x52).unwrap();
+        // Read Sign, Chip Erase, Leave Progmode, Enter Progmode, then the page
+        assert_eq!(commands[erase - 1..erase + 5], [0x75, 0x52, 0x51, 0x50, 0x55, 0x64]);
+        assert_eq!(commands.iter().filter(|&&c| c == 0x52).count(), 1);
+    }
+
+    #[test]
+    fn prog_memory_stores_osccal() {
+        let target = Target::new();
+        for (i, byte) in target.borrow_mut().eeprom.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let options = ProgOptions { osccal_address: Some(0x11), ..ProgOptions::default() };
+        run(&target, |client| client.prog_memory_with('E', 4, 1, vec![1, 2, 3, 4], &options)).unwrap();
+
+        let target = target.borrow();
+        assert_eq!(target.eeprom[..4], [1, 2, 3, 4]);
+        assert_eq!(target.eeprom[0x10..0x13], [0x10, 0x9a, 0x12]);
+        assert!(target.commands().contains(&0x76));
+
+        // Only EEPROM sessions take the byte
+        let target = Target::new();
+        run(&target, |client| client.prog_memory_with('F', 4, 2, vec![1, 2, 3, 4], &options)).unwrap();
+        let target = target.borrow();
+        assert!(!target.commands().contains(&0x76));
+        assert_eq!(target.flash[0x11], 0xff);
+    }
+
+    #[test]
+    fn eeprom_bytes_out_of_range() {
+        let target = Target::new();
+        let m328p = device::by_name("m328p").unwrap();
+        match run(&target, |client| client.write_eeprom_bytes(m328p, 0x3fc, &[0; 8])) {
+            Err(StkError::AddressOutOfRange { address: 0x400 }) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        match run(&target, |client| client.read_eeprom_bytes(m328p, 0xfff0, 0x20)) {
+            Err(StkError::AddressOutOfRange { address: 0xfff0 }) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(target.borrow().sent.is_empty());
+
+        run(&target, |client| client.write_eeprom_bytes(m328p, 0x3fc, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(target.borrow().sent.iter().filter(|command| command[0] == 0x61).count(), 4);
+    }
+
+    #[test]
+    fn prog_memory_autoinc_skips_address_loads() {
+        let image = || {
+            let mut image = MemoryImage::new();
+            image.insert(0, &[1; 0x100]);
+            image.insert(0x200, &[2; 0x80]);
+            image
+        };
+        let loads = |target: &Rc<RefCell<Target>>| -> Vec<Vec<u8>> {
+            target.borrow().sent.iter().filter(|command| command[0] == 0x55).cloned().collect()
+        };
+        let options = ProgOptions { autoinc: true, ..ProgOptions::default() };
+
+        // Loaded for the first page and after the gap only
+        let target = Target::new();
+        target.borrow_mut().autoinc = true;
+        run(&target, |client| client.prog_memory_with('F', 0x80, 2, image(), &options)).unwrap();
+        assert_eq!(loads(&target), [vec![0x55, 0x00, 0x00], vec![0x55, 0x00, 0x01]]);
+        assert_eq!(target.borrow().flash[..0x100], [1; 0x100][..]);
+        assert_eq!(target.borrow().flash[0x200..0x280], [2; 0x80][..]);
+
+        // Reading a page back moves the address, so the next one is loaded again
+        let target = Target::new();
+        target.borrow_mut().autoinc = true;
+        let verify = ProgOptions { verify: true, ..options.clone() };
+        run(&target, |client| client.prog_memory_with('F', 0x80, 2, image(), &verify)).unwrap();
+        assert_eq!(loads(&target).len(), 6);
+
+        // A target that doesn't auto-increment has every page loaded
+        let target = Target::new();
+        run(&target, |client| client.prog_memory_with('F', 0x80, 2, image(), &options)).unwrap();
+        assert_eq!(loads(&target).len(), 3);
+        assert_eq!(target.borrow().flash[0x80..0x100], [1; 0x80][..]);
+    }
+
+    #[test]
+    fn prog_memory_reports_progress() {
+        let target = Target::new();
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let _reports = reports.clone();
+        let options = ProgOptions {
+            verify: true,
+            progress: Some(ProgressCallback::new(move |progress| _reports.borrow_mut().push(progress))),
+            ..ProgOptions::default()
+        };
+        let mut image = MemoryImage::new();
+        image.insert(0, &[1; 0x80]);
+        image.insert(0x200, &[2, 3]);
+        run(&target, |client| client.prog_memory_with('F', 0x80, 2, image, &options)).unwrap();
+
+        assert_eq!(*reports.borrow(), [
+            Progress::Connected,
+            Progress::SignatureRead([0x1e, 0x95, 0x0f]),
+            Progress::PageWritten { address: 0, bytes: 0x80, total: 0x82 },
+            Progress::Verified { address: 0 },
+            Progress::PageWritten { address: 0x200, bytes: 0x82, total: 0x82 },
+            Progress::Verified { address: 0x200 },
+            Progress::Done,
+        ]);
+    }
+
+    #[test]
+    fn prog_memory_cancelled_before_erase() {
+        let target = Target::new();
+        let cancel = CancelHandle::new();
+        cancel.cancel();
+        let options = ProgOptions { erase: true, cancel: Some(cancel), ..ProgOptions::default() };
+        match run(&target, |client| client.prog_memory_with('F', 128, 2, vec![1, 2], &options)) {
+            Err(StkError::Cancelled) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        let commands = target.borrow().commands();
+        assert!(!commands.contains(&0x52));
+        assert!(!commands.contains(&0x64));
+        assert_eq!(commands.last(), Some(&0x51));
+    }
+
+    #[test]
+    fn retry_after_resync() {
+        let config = ClientConfig {
+            timeout: Some(Duration::from_millis(20)),
+            page_delay: Duration::from_millis(0),
+            retry: RetryPolicy { max_attempts: 2, ..RetryPolicy::default() },
+            ..ClientConfig::default()
+        };
+
+        let target = Target::new();
+        target.borrow_mut().ignore.push(0x75);
+        run_with(&target, config.clone(), |client| client.prog_memory('F', 128, 2, vec![1, 2])).unwrap();
+        let commands = target.borrow().commands();
+        let first = commands.iter().position(|&c| c == 0x75).unwrap();
+        // Sent again once the target answers GetSync
+        assert_eq!(commands[first..first + 5], [0x75, 0x30, 0x30, 0x30, 0x75]);
+        assert_eq!(target.borrow().flash[..2], [1, 2]);
+
+        // Writing a page isn't repeated
+        let target = Target::new();
+        target.borrow_mut().ignore.push(0x64);
+        match run_with(&target, config, |client| client.prog_memory('F', 128, 2, vec![1, 2])) {
+            Err(StkError::Timeout) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert_eq!(target.borrow().commands().iter().filter(|&&c| c == 0x64).count(), 1);
+    }
+
+    #[test]
+    fn prog_memory_eeprom_keeps_gaps() {
+        let target = Target::new();
+        target.borrow_mut().eeprom[..8].copy_from_slice(&[0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17]);
+        let mut image = MemoryImage::new();
+        image.insert(1, &[1]);
+        image.insert(6, &[6, 7]);
+        run(&target, |client| client.prog_memory('E', 4, 1, image)).unwrap();
+
+        let target = target.borrow();
+        assert_eq!(target.eeprom[..8], [0x10, 1, 0x12, 0x13, 0x14, 0x15, 6, 7]);
+    }
+
+    #[test]
+    fn prog_elf_eeprom_geometry() {
+        let mut eeprom = MemoryImage::new();
+        eeprom.insert(0x101, &[5, 6, 7, 8, 9]);
+        let elf = ElfImage { flash: vec![1, 2, 3, 4].into(), eeprom, fuses: Vec::new(), lock: None };
+        let target = Target::new();
+        run(&target, |client| client.prog_elf(elf, 128, 2)).unwrap();
 
+        let target = target.borrow();
+        assert_eq!(target.flash[..4], [1, 2, 3, 4]);
+        assert_eq!(target.eeprom[0x100..0x107], [0xff, 5, 6, 7, 8, 9, 0xff]);
+        // Byte addresses, in pages of the ATmega328P's 4 bytes
+        let pages: Vec<&[u8]> = target.sent.iter()
+            .filter(|command| command[0] == 0x64 && command[3] == b'E')
+            .map(|command| &command[1..3])
+            .collect();
+        assert_eq!(pages, [&[0, 3][..], &[0, 2][..]]);
+        assert!(target.sent.contains(&vec![0x55, 0x01, 0x01]));
     }
 }
@@ -0,0 +1,295 @@
+use std::borrow::Cow;
+use super::isp::{self, IspOp, Opcode};
+
+/// An AVR part, with the parameters the STK500 protocol needs to program it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+    /// avrdude's short name for the part, e.g. "m328p"
+    pub id: Cow<'static, str>,
+    pub name: Cow<'static, str>,
+    pub signature: [u8; 3],
+    /// STK500 device code. Parts avrdude gives no code of their own take
+    /// that of the built-in part with the same signature, or one supplied
+    /// through `avrdude_conf::parse_with_devicecodes`.
+    pub devicecode: u8,
+    pub flash_size: u32,
+    pub flash_page_size: u16,
+    pub eeprom_size: u16,
+    pub eeprom_page_size: u8,
+    pub fuse_bytes: u8,
+    pub lock_bytes: u8,
+    /// Values read back from flash while a write is still in progress
+    pub flash_poll: [u8; 2],
+    /// Values read back from EEPROM while a write is still in progress
+    pub eeprom_poll: [u8; 2],
+    /// Port pin used as PAGEL in high voltage parallel programming
+    pub pagel: u8,
+    /// Port pin used as BS2 in high voltage parallel programming
+    pub bs2: u8,
+    /// Whether the reset pin can be turned into an I/O pin by a fuse
+    pub reset_disable: bool,
+    /// How long a chip erase takes, in microseconds
+    pub chip_erase_delay: u32,
+    /// The part's ISP instruction templates
+    pub opcodes: Cow<'static, [IspOp]>,
+}
+
+impl Device {
+    /// Whether `name` is this part's name or id, ignoring case.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.id.eq_ignore_ascii_case(name)
+    }
+
+    /// The ISP instruction for `operation` on `memory`, if the part has one.
+    /// Part wide operations like "chip_erase" have an empty `memory`.
+    pub fn opcode(&self, memory: &str, operation: &str) -> Option<Opcode> {
+        self.opcodes.iter()
+            .find(|op| op.memory == memory && op.operation == operation)
+            .and_then(|op| Opcode::parse(&op.template).ok())
+    }
+
+    /// The parameters sent with `CmndStkSetDevice` for this part.
+    pub fn parameters(&self) -> DeviceParameters {
+        DeviceParameters {
+            devicecode: self.devicecode,
+            revision: 0,
+            progtype: 0,
+            parmode: 1,
+            polling: true,
+            selftimed: true,
+            lock_bytes: self.lock_bytes,
+            fuse_bytes: self.fuse_bytes,
+            flash_poll: self.flash_poll,
+            eeprom_poll: self.eeprom_poll,
+            page_size: self.flash_page_size,
+            eeprom_size: self.eeprom_size,
+            flash_size: self.flash_size,
+        }
+    }
+
+    /// The parameters sent with `CmndStkSetDeviceExt` for this part.
+    pub fn parameters_ext(&self) -> DeviceParametersExt {
+        DeviceParametersExt {
+            eeprom_page_size: self.eeprom_page_size,
+            signal_pagel: self.pagel,
+            signal_bs2: self.bs2,
+            reset_disable: self.reset_disable,
+        }
+    }
+}
+
+/// The payload of a `CmndStkSetDevice` command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceParameters {
+    pub devicecode: u8,
+    /// Device revision, currently unused by the STK500 firmware
+    pub revision: u8,
+    /// 0 if the part can be programmed in both parallel and serial mode,
+    /// 1 if only in parallel mode
+    pub progtype: u8,
+    /// 0 for a pseudo parallel interface, 1 for a full parallel interface
+    pub parmode: u8,
+    /// Whether the part supports polling while it writes
+    pub polling: bool,
+    /// Whether the part's programming instructions are self timed
+    pub selftimed: bool,
+    pub lock_bytes: u8,
+    pub fuse_bytes: u8,
+    pub flash_poll: [u8; 2],
+    pub eeprom_poll: [u8; 2],
+    /// Flash page size in bytes, 0 if flash isn't paged
+    pub page_size: u16,
+    pub eeprom_size: u16,
+    pub flash_size: u32,
+}
+
+impl DeviceParameters {
+    /// Length of the payload on the wire
+    pub const LEN: usize = 20;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            self.devicecode,
+            self.revision,
+            self.progtype,
+            self.parmode,
+            self.polling as u8,
+            self.selftimed as u8,
+            self.lock_bytes,
+            self.fuse_bytes,
+            self.flash_poll[0],
+            self.flash_poll[1],
+            self.eeprom_poll[0],
+            self.eeprom_poll[1],
+            (self.page_size >> 8) as u8,
+            self.page_size as u8,
+            (self.eeprom_size >> 8) as u8,
+            self.eeprom_size as u8,
+            (self.flash_size >> 24) as u8,
+            (self.flash_size >> 16) as u8,
+            (self.flash_size >> 8) as u8,
+            self.flash_size as u8,
+        ]
+    }
+
+    /// Decode a payload, e.g. one captured from another programmer. `None`
+    /// if it isn't `LEN` bytes long.
+    pub fn from_bytes(payload: &[u8]) -> Option<DeviceParameters> {
+        if payload.len() != Self::LEN {
+            return None;
+        }
+        let p = payload;
+        Some(DeviceParameters {
+            devicecode: p[0],
+            revision: p[1],
+            progtype: p[2],
+            parmode: p[3],
+            polling: p[4] != 0,
+            selftimed: p[5] != 0,
+            lock_bytes: p[6],
+            fuse_bytes: p[7],
+            flash_poll: [p[8], p[9]],
+            eeprom_poll: [p[10], p[11]],
+            page_size: (p[12] as u16) << 8 | p[13] as u16,
+            eeprom_size: (p[14] as u16) << 8 | p[15] as u16,
+            flash_size: (p[16] as u32) << 24 | (p[17] as u32) << 16 | (p[18] as u32) << 8 | p[19] as u32,
+        })
+    }
+}
+
+/// The payload of a `CmndStkSetDeviceExt` command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceParametersExt {
+    pub eeprom_page_size: u8,
+    /// Port pin used as PAGEL in high voltage parallel programming
+    pub signal_pagel: u8,
+    /// Port pin used as BS2 in high voltage parallel programming
+    pub signal_bs2: u8,
+    pub reset_disable: bool,
+}
+
+impl DeviceParametersExt {
+    /// Length of the payload on the wire, including its leading length byte
+    pub const LEN: usize = 5;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            Self::LEN as u8,
+            self.eeprom_page_size,
+            self.signal_pagel,
+            self.signal_bs2,
+            self.reset_disable as u8,
+        ]
+    }
+
+    /// Decode a payload. The 4 byte form older firmware takes, without the
+    /// reset disable flag, is accepted too. `None` if the length byte doesn't
+    /// match the payload.
+    pub fn from_bytes(payload: &[u8]) -> Option<DeviceParametersExt> {
+        match *payload {
+            [4, eeprom_page_size, signal_pagel, signal_bs2] => Some(DeviceParametersExt {
+                eeprom_page_size, signal_pagel, signal_bs2, reset_disable: false,
+            }),
+            [5, eeprom_page_size, signal_pagel, signal_bs2, reset_disable] => Some(DeviceParametersExt {
+                eeprom_page_size, signal_pagel, signal_bs2, reset_disable: reset_disable != 0,
+            }),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! device {
+    ($id:expr, $name:expr, $sig:expr, $code:expr, flash: $flash:expr, $page:expr,
+     eeprom: $eeprom:expr, $epage:expr, fuses: $fuses:expr, bs2: $bs2:expr, erase: $erase:expr) => {
+        Device {
+            id: Cow::Borrowed($id),
+            name: Cow::Borrowed($name),
+            signature: $sig,
+            devicecode: $code,
+            flash_size: $flash,
+            flash_page_size: $page,
+            eeprom_size: $eeprom,
+            eeprom_page_size: $epage,
+            fuse_bytes: $fuses,
+            lock_bytes: 1,
+            flash_poll: [0xff, 0xff],
+            eeprom_poll: [0xff, 0xff],
+            pagel: 0xd7,
+            bs2: $bs2,
+            reset_disable: false,
+            chip_erase_delay: $erase,
+            opcodes: Cow::Borrowed(if $fuses > 2 { isp::ATMEGA_EFUSE_OPCODES } else { isp::ATMEGA_OPCODES }),
+        }
+    }
+}
+
+pub static DEVICES: &[Device] = &[
+    device!("m8", "ATmega8", [0x1e, 0x93, 0x07], 0x70,
+            flash: 8192, 64, eeprom: 512, 4, fuses: 2, bs2: 0xc2, erase: 10000),
+    device!("m168", "ATmega168", [0x1e, 0x94, 0x06], 0x86,
+            flash: 16384, 128, eeprom: 512, 4, fuses: 3, bs2: 0xc2, erase: 9000),
+    device!("m168p", "ATmega168P", [0x1e, 0x94, 0x0b], 0x86,
+            flash: 16384, 128, eeprom: 512, 4, fuses: 3, bs2: 0xc2, erase: 9000),
+    device!("m328", "ATmega328", [0x1e, 0x95, 0x14], 0x86,
+            flash: 32768, 128, eeprom: 1024, 4, fuses: 3, bs2: 0xc2, erase: 9000),
+    device!("m328p", "ATmega328P", [0x1e, 0x95, 0x0f], 0x86,
+            flash: 32768, 128, eeprom: 1024, 4, fuses: 3, bs2: 0xc2, erase: 9000),
+    device!("m32u4", "ATmega32U4", [0x1e, 0x95, 0x87], 0x86,
+            flash: 32768, 128, eeprom: 1024, 4, fuses: 3, bs2: 0xa0, erase: 9000),
+    device!("m644p", "ATmega644P", [0x1e, 0x96, 0x0a], 0x82,
+            flash: 65536, 256, eeprom: 2048, 8, fuses: 3, bs2: 0xa0, erase: 55000),
+    device!("m1280", "ATmega1280", [0x1e, 0x97, 0x03], 0xb2,
+            flash: 131072, 256, eeprom: 4096, 8, fuses: 3, bs2: 0xa0, erase: 9000),
+    device!("m1284p", "ATmega1284P", [0x1e, 0x97, 0x05], 0x82,
+            flash: 131072, 256, eeprom: 4096, 8, fuses: 3, bs2: 0xa0, erase: 55000),
+    device!("m128rfa1", "ATmega128RFA1", [0x1e, 0xa7, 0x01], 0xb2,
+            flash: 131072, 256, eeprom: 4096, 8, fuses: 3, bs2: 0xe2, erase: 55000),
+    device!("m2560", "ATmega2560", [0x1e, 0x98, 0x01], 0xb2,
+            flash: 262144, 256, eeprom: 4096, 8, fuses: 3, bs2: 0xa0, erase: 9000),
+];
+
+/// The part assumed when none is given
+pub fn default_device() -> &'static Device {
+    by_name("ATmega328P").unwrap()
+}
+
+/// Look a part up by name or id, ignoring case: "atmega328p" and "m328p"
+/// both find the ATmega328P.
+pub fn by_name(name: &str) -> Option<&'static Device> {
+    DEVICES.iter().find(|d| d.matches(name))
+}
+
+pub fn by_signature(signature: [u8; 3]) -> Option<&'static Device> {
+    DEVICES.iter().find(|d| d.signature == signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{by_name, by_signature, default_device, DeviceParameters, DeviceParametersExt};
+
+    #[test]
+    fn lookup() {
+        let device = by_name("atmega328p").unwrap();
+        assert_eq!(device.signature, [0x1e, 0x95, 0x0f]);
+        assert_eq!(by_name("M328P"), Some(device));
+        assert_eq!(by_signature([0x1e, 0x95, 0x87]).unwrap().name, "ATmega32U4");
+        assert!(by_signature([0x00, 0x00, 0x00]).is_none());
+        assert!(device.opcode("efuse", "read").is_some());
+        assert!(by_name("m8").unwrap().opcode("efuse", "read").is_none());
+    }
+
+    #[test]
+    fn atmega328p_payloads() {
+        let device = default_device();
+        let parameters = device.parameters().to_bytes();
+        assert_eq!(parameters, vec![0x86, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01,
+            0x03, 0xff, 0xff, 0xff, 0xff, 0x00, 0x80, 0x04, 0x00,
+            0x00, 0x00, 0x80, 0x00]);
+        let parameters_ext = device.parameters_ext().to_bytes();
+        assert_eq!(parameters_ext, vec![0x05, 0x04, 0xd7, 0xc2, 0x00]);
+
+        assert_eq!(DeviceParameters::from_bytes(&parameters), Some(device.parameters()));
+        assert_eq!(DeviceParametersExt::from_bytes(&parameters_ext), Some(device.parameters_ext()));
+        assert_eq!(DeviceParametersExt::from_bytes(&parameters_ext[..4]), None);
+    }
+}
@@ -0,0 +1,183 @@
+use super::{MemoryImage, StkError};
+
+const EM_AVR: u16 = 83;
+const PT_LOAD: u32 = 1;
+const SHT_NOBITS: u32 = 8;
+
+/// Where avr-gcc's linker script puts the EEPROM contents
+pub const EEPROM_OFFSET: u32 = 0x81_0000;
+
+/// The memories held by an AVR ELF file.
+#[derive(Debug, Default)]
+pub struct ElfImage {
+    /// `.text` and `.data`, at their load addresses
+    pub flash: MemoryImage,
+    /// `.eeprom`, relative to the start of EEPROM
+    pub eeprom: MemoryImage,
+    /// `.fuse`: the low, high and extended fuse bytes, as many as the part has
+    pub fuses: Vec<u8>,
+    /// `.lock`
+    pub lock: Option<u8>,
+}
+
+struct Section<'a> {
+    name: &'a [u8],
+    load_address: u32,
+    data: &'a [u8],
+}
+
+/// Read the flash, EEPROM, fuse and lock contents out of a 32 bit little
+/// endian AVR ELF file, the way `avr-objcopy` would.
+pub fn parse(elf: &[u8]) -> Result<ElfImage, StkError> {
+    let mut image = ElfImage::default();
+    for section in sections(elf)? {
+        match section.name {
+            b".text" | b".data" => image.flash.insert(section.load_address, section.data),
+            b".eeprom" => {
+                let address = section.load_address.wrapping_sub(EEPROM_OFFSET);
+                image.eeprom.insert(address, section.data);
+            }
+            b".fuse" => image.fuses = section.data.to_vec(),
+            b".lock" => image.lock = section.data.first().cloned(),
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section<'_>>, StkError> {
+    if elf.len() < 52 || &elf[0..4] != b"\x7fELF" {
+        return Err(StkError::ParseElfError("not an ELF file"));
+    }
+    if elf[4] != 1 || elf[5] != 1 {
+        return Err(StkError::ParseElfError("not a 32 bit little endian ELF file"));
+    }
+    if u16_at(elf, 18)? != EM_AVR {
+        return Err(StkError::ParseElfError("not an AVR ELF file"));
+    }
+    let phoff = u32_at(elf, 28)? as usize;
+    let shoff = u32_at(elf, 32)? as usize;
+    let phentsize = u16_at(elf, 42)? as usize;
+    let phnum = u16_at(elf, 44)? as usize;
+    let shentsize = u16_at(elf, 46)? as usize;
+    let shnum = u16_at(elf, 48)? as usize;
+    let shstrndx = u16_at(elf, 50)? as usize;
+
+    // (offset, file size, physical address) of each loadable segment
+    let mut segments = Vec::with_capacity(phnum);
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        if u32_at(elf, ph)? == PT_LOAD {
+            segments.push((u32_at(elf, ph + 4)?, u32_at(elf, ph + 16)?, u32_at(elf, ph + 12)?));
+        }
+    }
+
+    let names = if shstrndx < shnum {
+        let sh = shoff + shstrndx * shentsize;
+        slice_at(elf, u32_at(elf, sh + 16)? as usize, u32_at(elf, sh + 20)? as usize)?
+    } else {
+        &[]
+    };
+
+    let mut sections = Vec::with_capacity(shnum);
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        let name_offset = u32_at(elf, sh)? as usize;
+        let sh_type = u32_at(elf, sh + 4)?;
+        let addr = u32_at(elf, sh + 12)?;
+        let offset = u32_at(elf, sh + 16)?;
+        let size = u32_at(elf, sh + 20)?;
+        if sh_type == SHT_NOBITS || size == 0 {
+            continue;
+        }
+        let name = names.get(name_offset..)
+            .and_then(|name| name.split(|&b| b == 0).next())
+            .ok_or(StkError::ParseElfError("bad section name"))?;
+        // Sections are loaded at the physical address of the segment holding
+        // them, which differs from their address for `.data`.
+        let load_address = segments.iter()
+            .find(|&&(p_offset, p_filesz, _)| {
+                p_offset <= offset && offset as u64 + size as u64 <= p_offset as u64 + p_filesz as u64
+            })
+            .map_or(addr, |&(p_offset, _, p_paddr)| p_paddr + (offset - p_offset));
+        sections.push(Section {
+            name,
+            load_address,
+            data: slice_at(elf, offset as usize, size as usize)?,
+        });
+    }
+    Ok(sections)
+}
+
+fn slice_at(elf: &[u8], offset: usize, len: usize) -> Result<&[u8], StkError> {
+    elf.get(offset..offset + len).ok_or(StkError::ParseElfError("truncated file"))
+}
+
+fn u16_at(elf: &[u8], offset: usize) -> Result<u16, StkError> {
+    let b = slice_at(elf, offset, 2)?;
+    Ok(b[0] as u16 | (b[1] as u16) << 8)
+}
+
+fn u32_at(elf: &[u8], offset: usize) -> Result<u32, StkError> {
+    let b = slice_at(elf, offset, 4)?;
+    Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    fn push_u16(buf: &mut Vec<u8>, v: u16) {
+        buf.extend_from_slice(&[v as u8, (v >> 8) as u8]);
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+    }
+
+    #[test]
+    fn parse_sections() {
+        let names = b"\0.text\0.data\0.eeprom\0.fuse\0.shstrtab\0";
+        let contents: [&[u8]; 5] = [&[0x0c, 0x94], &[0xaa, 0xbb], &[0x01], &[0xff, 0xd9, 0xfd], names];
+        // (name offset, address)
+        let headers = [(1, 0x0), (7, 0x80_0100), (13, 0x81_0010), (21, 0x82_0000), (27, 0x0)];
+
+        let mut elf = b"\x7fELF\x01\x01\x01".to_vec();
+        elf.resize(16, 0);
+        push_u16(&mut elf, 2); // e_type
+        push_u16(&mut elf, 83); // e_machine
+        push_u32(&mut elf, 1); // e_version
+        push_u32(&mut elf, 0); // e_entry
+        push_u32(&mut elf, 52); // e_phoff
+        push_u32(&mut elf, 52 + 32); // e_shoff
+        push_u32(&mut elf, 0); // e_flags
+        push_u16(&mut elf, 52); // e_ehsize
+        push_u16(&mut elf, 32); // e_phentsize
+        push_u16(&mut elf, 1); // e_phnum
+        push_u16(&mut elf, 40); // e_shentsize
+        push_u16(&mut elf, 5); // e_shnum
+        push_u16(&mut elf, 4); // e_shstrndx
+
+        let data_offset = 52 + 32 + 5 * 40;
+        // .data is loaded right after .text
+        for v in &[1, data_offset + 2, 0x80_0100, 2, 2, 2, 6, 1] {
+            push_u32(&mut elf, *v);
+        }
+        let mut offset = data_offset;
+        for (&(name, addr), data) in headers.iter().zip(contents.iter()) {
+            for v in &[name, 1, 0, addr, offset, data.len() as u32, 0, 0, 1, 0] {
+                push_u32(&mut elf, *v);
+            }
+            offset += data.len() as u32;
+        }
+        for data in contents.iter() {
+            elf.extend_from_slice(data);
+        }
+
+        let image = parse(&elf).unwrap();
+        assert_eq!(image.flash.to_buffer(), vec![0x0c, 0x94, 0xaa, 0xbb]);
+        assert_eq!(image.eeprom.get(0x10), Some(0x01));
+        assert_eq!(image.fuses, vec![0xff, 0xd9, 0xfd]);
+        assert_eq!(image.lock, None);
+    }
+}
@@ -0,0 +1,179 @@
+use futures::sync::oneshot;
+use super::device;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum StkError {
+    /// The target did not answer a command in time
+    Timeout,
+    /// The target answered with RespStkNosync, or with a frame that did not
+    /// start with RespStkInsync
+    OutOfSync,
+    /// The target answered with RespStkFailed
+    Failed,
+    /// The target answered with RespStkNodevice
+    NoDevice,
+    /// The target answered with RespStkUnknown
+    Unknown,
+    /// The signature read from the target is not the one we expected.
+    /// `expected_part` names the part expected, when it is known; parts are
+    /// otherwise looked up by signature.
+    SignatureMismatch { expected: [u8; 3], expected_part: Option<String>, actual: [u8; 3] },
+    /// A byte read back after programming differs from the one written
+    VerifyMismatch { address: usize, expected: u8, actual: Option<u8> },
+    /// A byte address the target can't be told to go to
+    AddressOutOfRange { address: usize },
+    /// The part's opcode table has no ISP instruction for `operation` on
+    /// `memory`
+    UnsupportedOperation { memory: String, operation: &'static str },
+    /// The fuse values would disable ISP programming or the reset pin
+    UnsafeFuses { low: u8, high: u8 },
+    /// Fuses were to be written to a part whose fuse layout isn't known, so
+    /// they can't be checked
+    UnknownFuseLayout { part: String },
+    /// A hex file could not be parsed. Lines and columns start at 1.
+    ParseHexFileError { line: usize, column: usize, reason: &'static str },
+    /// An S-record file could not be parsed. Lines and columns start at 1.
+    ParseSrecError { line: usize, column: usize, reason: &'static str },
+    /// An ELF file could not be parsed
+    ParseElfError(&'static str),
+    /// An ISP instruction template could not be parsed
+    ParseOpcodeError(&'static str),
+    /// An avrdude.conf file could not be parsed. Lines start at 1.
+    ParseConfError { line: usize, reason: &'static str },
+    /// The pending response was dropped before the target answered
+    Disconnected,
+    /// The session was stopped through its `CancelHandle`
+    Cancelled,
+    Io(io::Error),
+}
+
+impl fmt::Display for StkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StkError::Timeout => write!(f, "Timed out waiting for a response"),
+            StkError::OutOfSync => write!(f, "Target is out of sync"),
+            StkError::Failed => write!(f, "Target reported a failure"),
+            StkError::NoDevice => write!(f, "Target reported no device"),
+            StkError::Unknown => write!(f, "Target did not recognise the command"),
+            StkError::SignatureMismatch { expected, ref expected_part, actual } => write!(f,
+                "Signature mismatch: expected {:02x}{:02x}{:02x} ({}), read {:02x}{:02x}{:02x} ({})",
+                expected[0], expected[1], expected[2],
+                expected_part.as_deref().unwrap_or_else(|| part_name(expected)),
+                actual[0], actual[1], actual[2], part_name(actual)),
+            StkError::VerifyMismatch { address, expected, actual: Some(actual) } => write!(f,
+                "Verify error at address 0x{:04x}: expected 0x{:02x}, read 0x{:02x}",
+                address, expected, actual),
+            StkError::VerifyMismatch { address, expected, actual: None } => write!(f,
+                "Verify error at address 0x{:04x}: expected 0x{:02x}, read nothing",
+                address, expected),
+            StkError::AddressOutOfRange { address } => write!(f,
+                "Address 0x{:x} is out of range", address),
+            StkError::UnsupportedOperation { ref memory, operation } => write!(f,
+                "The part has no ISP instruction to {} {} memory", operation, memory),
+            StkError::UnsafeFuses { low, high } => write!(f,
+                "Refusing to write fuses 0x{:02x} 0x{:02x}: they would disable ISP programming or reset",
+                low, high),
+            StkError::UnknownFuseLayout { ref part } => write!(f,
+                "Refusing to write fuses: the fuse layout of {} is not known", part),
+            StkError::ParseHexFileError { line, column, reason } => write!(f,
+                "Error parsing hex file at line {}, column {}: {}", line, column, reason),
+            StkError::ParseSrecError { line, column, reason } => write!(f,
+                "Error parsing S-record file at line {}, column {}: {}", line, column, reason),
+            StkError::ParseElfError(reason) => write!(f, "Error parsing ELF file: {}", reason),
+            StkError::ParseOpcodeError(reason) => write!(f, "Error parsing ISP instruction template: {}", reason),
+            StkError::ParseConfError { line, reason } => write!(f,
+                "Error parsing avrdude.conf at line {}: {}", line, reason),
+            StkError::Disconnected => write!(f, "Response was dropped"),
+            StkError::Cancelled => write!(f, "Programming was cancelled"),
+            StkError::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+fn part_name(signature: [u8; 3]) -> &'static str {
+    device::by_signature(signature).map_or("unknown part", |d| &d.name)
+}
+
+impl Error for StkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StkError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// `io::Error`s coming out of the transport may be carrying an `StkError`;
+/// unwrap it if so.
+impl From<io::Error> for StkError {
+    fn from(e: io::Error) -> StkError {
+        if e.get_ref().is_some_and(|inner| inner.is::<StkError>()) {
+            let inner = e.into_inner().unwrap();
+            *inner.downcast::<StkError>().unwrap()
+        } else {
+            StkError::Io(e)
+        }
+    }
+}
+
+/// The transport layer only deals in `io::Error`s, so an `StkError` raised
+/// there travels wrapped inside one.
+impl From<StkError> for io::Error {
+    fn from(e: StkError) -> io::Error {
+        match e {
+            StkError::Io(e) => e,
+            StkError::Timeout => io::Error::new(io::ErrorKind::TimedOut, e),
+            e => io::Error::other(e),
+        }
+    }
+}
+
+impl From<oneshot::Canceled> for StkError {
+    fn from(_: oneshot::Canceled) -> StkError {
+        StkError::Disconnected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StkError;
+    use std::io;
+
+    #[test]
+    fn round_trip_through_io_error() {
+        let e: io::Error = StkError::NoDevice.into();
+        match StkError::from(e) {
+            StkError::NoDevice => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        let e: io::Error = StkError::Timeout.into();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        match StkError::from(e) {
+            StkError::Timeout => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        match StkError::from(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe")) {
+            StkError::Io(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn signature_mismatch_names_parts() {
+        let e = StkError::SignatureMismatch {
+            expected: [0x1e, 0x95, 0x0f], expected_part: None, actual: [0x1e, 0x95, 0x87],
+        };
+        assert_eq!(e.to_string(), "Signature mismatch: expected 1e950f (ATmega328P), read 1e9587 (ATmega32U4)");
+
+        // A part from avrdude.conf
+        let e = StkError::SignatureMismatch {
+            expected: [0x1e, 0x93, 0x0b], expected_part: Some("ATtiny85".to_string()), actual: [0x1e, 0x95, 0x0f],
+        };
+        assert_eq!(e.to_string(), "Signature mismatch: expected 1e930b (ATtiny85), read 1e950f (ATmega328P)");
+    }
+}
@@ -0,0 +1,67 @@
+use std::str;
+use super::{elf, ihex, srec, MemoryImage, StkError};
+
+/// The firmware file formats that can be loaded into a `MemoryImage`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    IntelHex,
+    SRecord,
+    Elf,
+    Binary,
+}
+
+/// Guess the format of a firmware file from its contents. Anything that isn't
+/// recognisable as a text format is taken to be raw binary.
+pub fn detect_format(contents: &[u8]) -> FileFormat {
+    if contents.starts_with(b"\x7fELF") {
+        return FileFormat::Elf;
+    }
+    let text = match str::from_utf8(contents) {
+        Ok(text) => text.trim_start(),
+        Err(_) => return FileFormat::Binary,
+    };
+    let is_text = text.chars().all(|c| c.is_ascii_graphic() || c.is_ascii_whitespace());
+    let mut chars = text.chars();
+    match (is_text, chars.next(), chars.next()) {
+        (true, Some(':'), Some(c)) if c.is_ascii_hexdigit() => FileFormat::IntelHex,
+        (true, Some('S'), Some(c)) if c.is_ascii_digit() => FileFormat::SRecord,
+        _ => FileFormat::Binary,
+    }
+}
+
+/// Load raw binary data, placing its first byte at `base_address`.
+pub fn binary_to_image(data: &[u8], base_address: u32) -> MemoryImage {
+    let mut image = MemoryImage::new();
+    image.insert(base_address, data);
+    image
+}
+
+/// Load a firmware file in any supported format, detecting which from its
+/// contents. Raw binary files are placed at `binary_base_address`. Only the
+/// flash contents of an ELF file are returned; use `elf::parse` for the rest.
+pub fn load_image(contents: &[u8], binary_base_address: u32) -> Result<MemoryImage, StkError> {
+    match detect_format(contents) {
+        // detect_format only reports text formats for valid UTF-8
+        FileFormat::IntelHex => Ok(ihex::parse(str::from_utf8(contents).unwrap())?.image),
+        FileFormat::SRecord => Ok(srec::parse(str::from_utf8(contents).unwrap())?.image),
+        FileFormat::Elf => Ok(elf::parse(contents)?.flash),
+        FileFormat::Binary => Ok(binary_to_image(contents, binary_base_address)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_format, load_image, FileFormat};
+
+    #[test]
+    fn detect() {
+        assert_eq!(detect_format(b":00000001FF\n"), FileFormat::IntelHex);
+        assert_eq!(detect_format(b"\r\nS9030000FC\n"), FileFormat::SRecord);
+        assert_eq!(detect_format(b":\x0c\x94\x5c\x00"), FileFormat::Binary);
+        assert_eq!(detect_format(&[0x0c, 0x94, 0x5c, 0x00]), FileFormat::Binary);
+        assert_eq!(detect_format(b"\x7fELF\x01\x01\x01"), FileFormat::Elf);
+
+        let image = load_image(&[0x0c, 0x94], 0x100).unwrap();
+        assert_eq!(image.get(0x101), Some(0x94));
+    }
+}
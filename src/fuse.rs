@@ -0,0 +1,109 @@
+use super::{Device, StkError};
+
+// A programmed fuse bit reads as 0. Fuse bits are given as masks over the
+// low and high fuse bytes, in that order.
+
+const LOW: usize = 0;
+const HIGH: usize = 1;
+
+/// Fuse bits that decide whether a part stays reachable by an ISP
+/// programmer.
+struct IspFuseBits {
+    /// SPIEN: programmed while serial programming is enabled
+    spien: (usize, u8),
+    /// Bits that must stay unprogrammed, such as DWEN, which disables SPI
+    /// while debugWIRE is enabled, and RSTDISBL, which turns the reset pin
+    /// into an I/O pin
+    lockout: [u8; 2],
+}
+
+/// Where `device` keeps its ISP fuse bits, for the parts whose layout is
+/// known.
+fn isp_fuse_bits(device: &Device) -> Option<IspFuseBits> {
+    let bits = |spien, lockout| Some(IspFuseBits { spien, lockout });
+    match &*device.id {
+        // RSTDISBL 7
+        "m8" | "m8a" => bits((HIGH, 0x20), [0, 0x80]),
+        // RSTDISBL 7, DWEN 6
+        "m48" | "m48p" | "m88" | "m88p" | "m168" | "m168p" | "m328" | "m328p"
+            | "t24" | "t44" | "t84" | "t25" | "t45" | "t85" => bits((HIGH, 0x20), [0, 0xc0]),
+        // DWEN 7, RSTDISBL 0
+        "t2313" | "t4313" => bits((HIGH, 0x20), [0, 0x81]),
+        // SPIEN is bit 7 of the low fuse; DWEN 3, RSTDISBL 0
+        "t13" => bits((LOW, 0x80), [0, 0x09]),
+        // Parts with JTAG have no debugWIRE and no RSTDISBL; bit 7 is OCDEN
+        "m32u4" | "m644p" | "m1280" | "m1284p" | "m128rfa1" | "m2560" => bits((HIGH, 0x20), [0, 0]),
+        _ => None,
+    }
+}
+
+/// The fuse bytes of a part.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    /// Only present on parts with three fuse bytes
+    pub extended: Option<u8>,
+}
+
+/// Refuse fuse values that would disable serial programming, debugWIRE
+/// included, or turn `device`'s reset pin into an I/O pin. Parts set up that
+/// way can only be recovered with a high voltage programmer. Parts whose fuse
+/// layout isn't known are refused whatever the values.
+pub fn check_fuses(device: &Device, fuses: &Fuses) -> Result<(), StkError> {
+    let bits = isp_fuse_bits(device).ok_or_else(|| StkError::UnknownFuseLayout { part: device.name.to_string() })?;
+    let bytes = [fuses.low, fuses.high];
+    let (spien_byte, spien) = bits.spien;
+    if bytes[spien_byte] & spien != 0 || bytes.iter().zip(bits.lockout.iter()).any(|(&b, &m)| !b & m != 0) {
+        return Err(StkError::UnsafeFuses { low: fuses.low, high: fuses.high });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_fuses, Fuses};
+    use super::super::{device, StkError};
+
+    #[test]
+    fn unsafe_fuses() {
+        let m328p = device::by_name("m328p").unwrap();
+        let m2560 = device::by_name("m2560").unwrap();
+        // Arduino defaults
+        assert!(check_fuses(m328p, &Fuses { low: 0xff, high: 0xde, extended: Some(0xfd) }).is_ok());
+        assert!(check_fuses(m2560, &Fuses { low: 0xff, high: 0xd8, extended: Some(0xfd) }).is_ok());
+        // SPIEN unprogrammed
+        assert!(check_fuses(m2560, &Fuses { low: 0xff, high: 0xf8, extended: Some(0xfd) }).is_err());
+        // RSTDISBL programmed
+        assert!(check_fuses(m328p, &Fuses { low: 0xff, high: 0x5e, extended: Some(0xfd) }).is_err());
+        // Bit 7 is OCDEN on the ATmega2560
+        assert!(check_fuses(m2560, &Fuses { low: 0xff, high: 0x58, extended: Some(0xfd) }).is_ok());
+    }
+
+    #[test]
+    fn unsafe_fuses_by_layout() {
+        let part = |id: &str| {
+            let mut device = device::by_name("m328p").unwrap().clone();
+            device.id = id.to_string().into();
+            device
+        };
+        let (t13, t2313) = (part("t13"), part("t2313"));
+        // Factory defaults
+        assert!(check_fuses(&t13, &Fuses { low: 0x6a, high: 0xff, extended: None }).is_ok());
+        assert!(check_fuses(&t2313, &Fuses { low: 0x64, high: 0xdf, extended: None }).is_ok());
+        // SPIEN unprogrammed: the low fuse on the ATtiny13
+        assert!(check_fuses(&t13, &Fuses { low: 0xea, high: 0xff, extended: None }).is_err());
+        assert!(check_fuses(&t2313, &Fuses { low: 0x64, high: 0xff, extended: None }).is_err());
+        // RSTDISBL programmed
+        assert!(check_fuses(&t13, &Fuses { low: 0x6a, high: 0xfe, extended: None }).is_err());
+        assert!(check_fuses(&t2313, &Fuses { low: 0x64, high: 0xde, extended: None }).is_err());
+        // DWEN programmed
+        assert!(check_fuses(&t13, &Fuses { low: 0x6a, high: 0xf7, extended: None }).is_err());
+        assert!(check_fuses(&t2313, &Fuses { low: 0x64, high: 0x5f, extended: None }).is_err());
+
+        match check_fuses(&part("x128a1"), &Fuses { low: 0xff, high: 0xdf, extended: None }) {
+            Err(StkError::UnknownFuseLayout { ref part }) if part == "ATmega328P" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use super::{MemoryImage, StkError};
+
+/// Execution start address given by a type 03 or 05 record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryPoint {
+    Segment { cs: u16, ip: u16 },
+    Linear(u32),
+}
+
+/// The contents of an Intel HEX file.
+#[derive(Debug, Default)]
+pub struct HexFile {
+    pub image: MemoryImage,
+    pub entry_point: Option<EntryPoint>,
+}
+
+/// Which kind of record `write` uses to reach addresses above 64 KiB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Addressing {
+    /// Type 02 records, as written by avrdude. Only reaches the first 1 MiB;
+    /// linear records are used beyond that.
+    Segment,
+    /// Type 04 records
+    Linear,
+}
+
+enum Record {
+    Data { offset: u16, data: Vec<u8> },
+    EndOfFile,
+    ExtendedSegmentAddress(u16),
+    StartSegmentAddress { cs: u16, ip: u16 },
+    ExtendedLinearAddress(u16),
+    StartLinearAddress(u32),
+}
+
+/// Parse an Intel HEX file. Every record's checksum is checked, and the file
+/// must be terminated by an end of file record.
+pub fn parse(hex_string: &str) -> Result<HexFile, StkError> {
+    let mut file = HexFile::default();
+    let mut base_address = 0;
+    let mut lines = 0;
+    for (line_no, line) in hex_string.lines().enumerate() {
+        lines = line_no + 1;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let record = parse_record(line).map_err(|(column, reason)| {
+            StkError::ParseHexFileError { line: line_no + 1, column, reason }
+        })?;
+        match record {
+            Record::Data { offset, data } => {
+                let address = base_address + offset as u32;
+                if address.checked_add(data.len() as u32).is_none() {
+                    return Err(StkError::ParseHexFileError {
+                        line: line_no + 1, column: 4, reason: "data runs past the end of the address space",
+                    });
+                }
+                file.image.insert(address, &data);
+            }
+            Record::EndOfFile => {
+                return Ok(file);
+            }
+            Record::ExtendedSegmentAddress(segment) => {
+                base_address = (segment as u32) << 4;
+            }
+            Record::ExtendedLinearAddress(upper) => {
+                base_address = (upper as u32) << 16;
+            }
+            Record::StartSegmentAddress { cs, ip } => {
+                file.entry_point = Some(EntryPoint::Segment { cs, ip });
+            }
+            Record::StartLinearAddress(address) => {
+                file.entry_point = Some(EntryPoint::Linear(address));
+            }
+        }
+    }
+    Err(StkError::ParseHexFileError { line: lines + 1, column: 1, reason: "missing end of file record" })
+}
+
+/// Parse a single record. Errors carry the 1-based column they were found at.
+fn parse_record(line: &str) -> Result<Record, (usize, &'static str)> {
+    if !line.starts_with(':') {
+        return Err((1, "missing ':' start code"));
+    }
+    let digits = &line.as_bytes()[1..];
+    if digits.len() & 1 == 1 {
+        return Err((line.len(), "odd number of hex digits"));
+    }
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    for (i, pair) in digits.chunks(2).enumerate() {
+        let hi = hex_digit(pair[0]).ok_or((2 + i * 2, "invalid hex digit"))?;
+        let lo = hex_digit(pair[1]).ok_or((3 + i * 2, "invalid hex digit"))?;
+        bytes.push(hi << 4 | lo);
+    }
+    if bytes.len() < 5 {
+        return Err((line.len(), "record too short"));
+    }
+    let byte_count = bytes[0] as usize;
+    if bytes.len() != byte_count + 5 {
+        return Err((2, "byte count doesn't match record length"));
+    }
+    let checksum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    if checksum != 0 {
+        return Err((line.len() - 1, "checksum mismatch"));
+    }
+
+    let offset = (bytes[1] as u16) << 8 | bytes[2] as u16;
+    let data = &bytes[4..4 + byte_count];
+    let expect_len = |len: usize| {
+        if byte_count == len { Ok(()) } else { Err((2, "wrong byte count for record type")) }
+    };
+    match bytes[3] {
+        0x00 => Ok(Record::Data { offset, data: data.to_vec() }),
+        0x01 => {
+            expect_len(0)?;
+            Ok(Record::EndOfFile)
+        }
+        0x02 => {
+            expect_len(2)?;
+            Ok(Record::ExtendedSegmentAddress(be_u16(data)))
+        }
+        0x03 => {
+            expect_len(4)?;
+            Ok(Record::StartSegmentAddress { cs: be_u16(&data[0..2]), ip: be_u16(&data[2..4]) })
+        }
+        0x04 => {
+            expect_len(2)?;
+            Ok(Record::ExtendedLinearAddress(be_u16(data)))
+        }
+        0x05 => {
+            expect_len(4)?;
+            Ok(Record::StartLinearAddress((be_u16(&data[0..2]) as u32) << 16 | be_u16(&data[2..4]) as u32))
+        }
+        _ => Err((8, "unknown record type")),
+    }
+}
+
+/// Write `file` out as Intel HEX with at most `record_len` data bytes per
+/// record. `record_len` is brought into the 1 to 255 bytes a record can hold.
+pub fn write(file: &HexFile, record_len: usize, addressing: Addressing) -> String {
+    let record_len = record_len.clamp(1, 0xff);
+    let mut out = String::new();
+    // The upper address bits currently selected by an extended address record
+    let mut base_address = 0;
+    for segment in file.image.segments() {
+        let mut address = segment.address;
+        while address < segment.end() {
+            let upper = address & 0xffff_0000;
+            if upper != base_address {
+                if addressing == Addressing::Segment && address < 0x10_0000 {
+                    write_record(&mut out, 0x02, 0, &[(upper >> 12) as u8, 0]);
+                } else {
+                    write_record(&mut out, 0x04, 0, &[(upper >> 24) as u8, (upper >> 16) as u8]);
+                }
+                base_address = upper;
+            }
+            // Records must not run across a 64 KiB boundary
+            let end = segment.end()
+                .min(address + record_len as u32)
+                .min(upper + 0x1_0000);
+            let start = (address - segment.address) as usize;
+            let stop = (end - segment.address) as usize;
+            write_record(&mut out, 0x00, address as u16, &segment.data[start..stop]);
+            address = end;
+        }
+    }
+    match file.entry_point {
+        Some(EntryPoint::Segment { cs, ip }) => {
+            write_record(&mut out, 0x03, 0, &[(cs >> 8) as u8, cs as u8, (ip >> 8) as u8, ip as u8]);
+        }
+        Some(EntryPoint::Linear(address)) => {
+            write_record(&mut out, 0x05, 0, &[
+                (address >> 24) as u8, (address >> 16) as u8, (address >> 8) as u8, address as u8]);
+        }
+        None => {}
+    }
+    write_record(&mut out, 0x01, 0, &[]);
+    out
+}
+
+fn write_record(out: &mut String, record_type: u8, offset: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, record_type];
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    bytes.push(checksum.wrapping_neg());
+
+    out.push(':');
+    for b in bytes {
+        out.push_str(&format!("{:02X}", b));
+    }
+    out.push('\n');
+}
+
+pub(crate) fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, write, Addressing, EntryPoint, HexFile};
+    use super::super::{MemoryImage, StkError};
+
+    #[test]
+    fn parse_records() {
+        let hex = ":020000040001F9\n\
+                   :0400100001020304E2\n\
+                   :0400000500010000F6\n\
+                   :00000001FF\n";
+        let file = parse(hex).unwrap();
+        assert_eq!(file.image.segments().len(), 1);
+        assert_eq!(file.image.segments()[0].address, 0x10010);
+        assert_eq!(file.image.segments()[0].data, vec![1, 2, 3, 4]);
+        assert_eq!(file.entry_point, Some(EntryPoint::Linear(0x10000)));
+    }
+
+    #[test]
+    fn parse_errors() {
+        fn position(hex: &str) -> (usize, usize) {
+            match parse(hex) {
+                Err(StkError::ParseHexFileError { line, column, .. }) => (line, column),
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+        // Bad checksum
+        assert_eq!(position(":0400100001020304E2\n:0400100001020304E3\n:00000001FF\n"), (2, 18));
+        // Missing start code
+        assert_eq!(position("0400100001020304E2\n:00000001FF\n"), (1, 1));
+        // Invalid digit
+        assert_eq!(position(":04001000010G0304E2\n:00000001FF\n"), (1, 13));
+        // Truncated file
+        assert_eq!(position(":0400100001020304E2\n"), (2, 1));
+        // Data past 0xffffffff
+        assert_eq!(position(":02000004FFFFFC\n:02FFFF000102FD\n:00000001FF\n"), (2, 4));
+    }
+
+    #[test]
+    fn write_records() {
+        let mut image = MemoryImage::new();
+        image.insert(0xfffe, &[1, 2, 3, 4]);
+        let file = HexFile { image, entry_point: None };
+        assert_eq!(write(&file, 16, Addressing::Segment),
+                   ":02FFFE000102FE\n\
+                    :020000021000EC\n\
+                    :020000000304F7\n\
+                    :00000001FF\n");
+
+        let file = HexFile { image: file.image, entry_point: Some(EntryPoint::Linear(0x10000)) };
+        let hex = write(&file, 1, Addressing::Linear);
+        let parsed = parse(&hex).unwrap();
+        assert_eq!(parsed.image, file.image);
+        assert_eq!(parsed.entry_point, file.entry_point);
+
+        assert_eq!(write(&file, 0, Addressing::Linear), hex);
+        assert_eq!(parse(&write(&file, 1000, Addressing::Linear)).unwrap().image, file.image);
+    }
+}
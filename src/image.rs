@@ -0,0 +1,205 @@
+use std::cmp;
+use std::convert::TryFrom;
+
+/// A run of contiguous bytes starting at `address`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// The address one past the last byte of the segment
+    pub fn end(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+/// A sparse memory image: only addresses that were actually given data are
+/// held, so gaps can be told apart from bytes that happen to be 0xff.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryImage {
+    // Sorted by address. Segments never overlap or touch; adjacent data is
+    // merged into a single segment.
+    segments: Vec<Segment>,
+}
+
+impl MemoryImage {
+    pub fn new() -> MemoryImage {
+        MemoryImage { segments: Vec::new() }
+    }
+
+    /// Add `data` at `address`, replacing anything already there.
+    ///
+    /// # Panics
+    ///
+    /// If `data` runs past the end of the 32-bit address space.
+    pub fn insert(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = u32::try_from(data.len()).ok()
+            .and_then(|len| address.checked_add(len))
+            .expect("data runs past the end of the address space");
+        let first = self.segments.iter().position(|s| s.end() >= address).unwrap_or(self.segments.len());
+        let last = self.segments.iter().rposition(|s| s.address <= end).map_or(first, |i| i + 1);
+        if first >= last {
+            self.segments.insert(first, Segment { address, data: data.to_vec() });
+            return;
+        }
+
+        let merged: Vec<Segment> = self.segments.drain(first..last).collect();
+        let start = cmp::min(address, merged[0].address);
+        let stop = cmp::max(end, merged[merged.len() - 1].end());
+        let mut buf = vec![0xff; (stop - start) as usize];
+        for segment in merged.iter() {
+            let offset = (segment.address - start) as usize;
+            buf[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        let offset = (address - start) as usize;
+        buf[offset..offset + data.len()].copy_from_slice(data);
+        self.segments.insert(first, Segment { address: start, data: buf });
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The byte at `address`, if the image holds one
+    pub fn get(&self, address: u32) -> Option<u8> {
+        self.segments.iter()
+            .find(|s| s.address <= address && address < s.end())
+            .map(|s| s.data[(address - s.address) as usize])
+    }
+
+    /// The address one past the last byte in the image
+    pub fn end(&self) -> u32 {
+        self.segments.last().map_or(0, |s| s.end())
+    }
+
+    /// Split the image into `page_size` aligned pages, skipping pages that
+    /// hold no data. Each page runs from its start up to the last byte of data
+    /// it holds; gaps inside it are filled with 0xff.
+    pub fn pages(&self, page_size: usize) -> Vec<(u32, Vec<u8>)> {
+        let page_size = page_size as u32;
+        let mut pages: Vec<(u32, Vec<u8>)> = Vec::new();
+        for segment in self.segments.iter() {
+            let mut address = segment.address;
+            while address < segment.end() {
+                let page_start = address - address % page_size;
+                let chunk_end = cmp::min(page_start + page_size, segment.end());
+                let chunk = &segment.data[(address - segment.address) as usize..(chunk_end - segment.address) as usize];
+                let is_same_page = pages.last().is_some_and(|&(start, _)| start == page_start);
+                if !is_same_page {
+                    pages.push((page_start, Vec::new()));
+                }
+                let page = &mut pages.last_mut().unwrap().1;
+                page.resize((address - page_start) as usize, 0xff);
+                page.extend_from_slice(chunk);
+                address = chunk_end;
+            }
+        }
+        pages
+    }
+
+    /// Like `pages`, without the padding: each chunk is a run of data within
+    /// one page, at its own address. For EEPROM, where a page write only
+    /// touches the bytes it is given, so that cells outside the image keep
+    /// their contents.
+    pub fn runs(&self, page_size: usize) -> Vec<(u32, Vec<u8>)> {
+        let page_size = page_size as u32;
+        let mut runs = Vec::new();
+        for segment in self.segments.iter() {
+            let mut address = segment.address;
+            while address < segment.end() {
+                let page_start = address - address % page_size;
+                let chunk_end = cmp::min(page_start + page_size, segment.end());
+                let chunk = &segment.data[(address - segment.address) as usize..(chunk_end - segment.address) as usize];
+                runs.push((address, chunk.to_vec()));
+                address = chunk_end;
+            }
+        }
+        runs
+    }
+
+    /// Flatten the image into a buffer starting at address 0, filling gaps
+    /// with 0xff.
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = vec![0xff; self.end() as usize];
+        for segment in self.segments.iter() {
+            let start = segment.address as usize;
+            buffer[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        buffer
+    }
+}
+
+/// A dense buffer is taken to start at address 0.
+impl From<Vec<u8>> for MemoryImage {
+    fn from(data: Vec<u8>) -> MemoryImage {
+        let mut image = MemoryImage::new();
+        image.insert(0, &data);
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryImage, Segment};
+
+    #[test]
+    fn insert_merges() {
+        let mut image = MemoryImage::new();
+        image.insert(0x10, &[1, 2]);
+        image.insert(0x20, &[5]);
+        image.insert(0x12, &[3, 4]);
+        image.insert(0x11, &[9]);
+        assert_eq!(image.segments(), &[
+            Segment { address: 0x10, data: vec![1, 9, 3, 4] },
+            Segment { address: 0x20, data: vec![5] },
+        ]);
+        image.insert(0x14, &[0; 12]);
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.get(0x20), Some(5));
+        assert_eq!(image.get(0x21), None);
+    }
+
+    #[test]
+    fn pages_skip_gaps() {
+        let mut image = MemoryImage::new();
+        image.insert(0x02, &[1, 2]);
+        image.insert(0x06, &[3, 4, 5]);
+        image.insert(0x3f000, &[0xff, 0xff]);
+        assert_eq!(image.pages(4), vec![
+            (0x00, vec![0xff, 0xff, 1, 2]),
+            (0x04, vec![0xff, 0xff, 3, 4]),
+            (0x08, vec![5]),
+            (0x3f000, vec![0xff, 0xff]),
+        ]);
+    }
+
+    #[test]
+    fn runs_keep_gaps() {
+        let mut image = MemoryImage::new();
+        image.insert(0x02, &[1, 2]);
+        image.insert(0x06, &[3, 4, 5]);
+        image.insert(0x3f001, &[6]);
+        assert_eq!(image.runs(4), vec![
+            (0x02, vec![1, 2]),
+            (0x06, vec![3, 4]),
+            (0x08, vec![5]),
+            (0x3f001, vec![6]),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "past the end of the address space")]
+    fn insert_past_address_space() {
+        let mut image = MemoryImage::new();
+        image.insert(0xffff_ffff, &[1, 2]);
+    }
+}
@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+use super::StkError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Bit {
+    Zero,
+    One,
+    /// 'x': don't care, sent as 0
+    Ignore,
+    /// 'a<n>': bit n of the address
+    Address(u8),
+    /// 'i': a bit of the byte being written
+    Input,
+    /// 'o': a bit of the byte being read
+    Output,
+}
+
+/// A 32 bit ISP instruction template, in the notation avrdude.conf uses:
+/// 32 space separated bits, most significant first, each one of `0`, `1`,
+/// `x` (don't care), `a<n>` (address bit n), `i` (input bit) or `o` (output
+/// bit).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Opcode {
+    bits: [Bit; 32],
+}
+
+impl Opcode {
+    pub fn parse(template: &str) -> Result<Opcode, StkError> {
+        let mut bits = [Bit::Ignore; 32];
+        let mut count = 0;
+        for token in template.split_whitespace() {
+            if count == 32 {
+                return Err(StkError::ParseOpcodeError("more than 32 bits"));
+            }
+            bits[count] = match token {
+                "0" => Bit::Zero,
+                "1" => Bit::One,
+                "x" => Bit::Ignore,
+                "i" => Bit::Input,
+                "o" => Bit::Output,
+                _ if token.starts_with('a') => match token[1..].parse() {
+                    Ok(n) if n < 32 => Bit::Address(n),
+                    _ => return Err(StkError::ParseOpcodeError("invalid address bit")),
+                },
+                _ => return Err(StkError::ParseOpcodeError("invalid bit")),
+            };
+            count += 1;
+        }
+        if count != 32 {
+            return Err(StkError::ParseOpcodeError("fewer than 32 bits"));
+        }
+        Ok(Opcode { bits })
+    }
+
+    /// The instruction bytes for `address`, with `input` filled into the `i`
+    /// bits.
+    pub fn instruction(&self, address: u32, input: u8) -> [u8; 4] {
+        let mut instruction = [0; 4];
+        for (i, bit) in self.bits.iter().enumerate() {
+            let position = 7 - i % 8;
+            let value = match *bit {
+                Bit::Zero | Bit::Ignore | Bit::Output => 0,
+                Bit::One => 1,
+                Bit::Address(n) => (address >> n) as u8 & 1,
+                Bit::Input => input >> position & 1,
+            };
+            instruction[i / 8] |= value << position;
+        }
+        instruction
+    }
+
+    /// Pick the `o` bits out of the last byte the target sent back, which is
+    /// the byte `CmndStkUniversal` returns.
+    pub fn output(&self, result: u8) -> u8 {
+        self.bits[24..].iter().enumerate()
+            .filter(|&(_, bit)| *bit == Bit::Output)
+            .fold(0, |output, (i, _)| output | (result & 0x80 >> i))
+    }
+}
+
+/// One entry of a part's ISP instruction table: how to perform `operation`
+/// ("read", "write", "loadpage_lo", ...) on `memory` ("flash", "lfuse", ...).
+/// Operations on the part as a whole, like "pgm_enable", have an empty
+/// `memory`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IspOp {
+    pub memory: Cow<'static, str>,
+    pub operation: Cow<'static, str>,
+    pub template: Cow<'static, str>,
+}
+
+macro_rules! op {
+    ($memory:expr, $operation:expr, $template:expr) => {
+        IspOp {
+            memory: Cow::Borrowed($memory),
+            operation: Cow::Borrowed($operation),
+            template: Cow::Borrowed($template),
+        }
+    }
+}
+
+/// The instructions shared by the ATmegas with two fuse bytes, like the
+/// ATmega8
+pub static ATMEGA_OPCODES: &[IspOp] = &[
+    op!("", "pgm_enable", "1 0 1 0 1 1 0 0  0 1 0 1 0 0 1 1  x x x x x x x x  x x x x x x x x"),
+    op!("", "chip_erase", "1 0 1 0 1 1 0 0  1 0 0 x x x x x  x x x x x x x x  x x x x x x x x"),
+    op!("signature", "read", "0 0 1 1 0 0 0 0  0 0 0 x x x x x  x x x x x x a1 a0  o o o o o o o o"),
+    op!("calibration", "read", "0 0 1 1 1 0 0 0  0 0 0 x x x x x  0 0 0 0 0 0 a1 a0  o o o o o o o o"),
+    op!("lfuse", "read", "0 1 0 1 0 0 0 0  0 0 0 0 0 0 0 0  x x x x x x x x  o o o o o o o o"),
+    op!("lfuse", "write", "1 0 1 0 1 1 0 0  1 0 1 0 0 0 0 0  x x x x x x x x  i i i i i i i i"),
+    op!("hfuse", "read", "0 1 0 1 1 0 0 0  0 0 0 0 1 0 0 0  x x x x x x x x  o o o o o o o o"),
+    op!("hfuse", "write", "1 0 1 0 1 1 0 0  1 0 1 0 1 0 0 0  x x x x x x x x  i i i i i i i i"),
+    op!("lock", "read", "0 1 0 1 1 0 0 0  0 0 0 0 0 0 0 0  x x x x x x x x  x x o o o o o o"),
+    op!("lock", "write", "1 0 1 0 1 1 0 0  1 1 1 x x x x x  x x x x x x x x  1 1 i i i i i i"),
+];
+
+/// The instructions shared by the ATmegas with three fuse bytes
+pub static ATMEGA_EFUSE_OPCODES: &[IspOp] = &[
+    op!("", "pgm_enable", "1 0 1 0 1 1 0 0  0 1 0 1 0 0 1 1  x x x x x x x x  x x x x x x x x"),
+    op!("", "chip_erase", "1 0 1 0 1 1 0 0  1 0 0 x x x x x  x x x x x x x x  x x x x x x x x"),
+    op!("signature", "read", "0 0 1 1 0 0 0 0  0 0 0 x x x x x  x x x x x x a1 a0  o o o o o o o o"),
+    op!("calibration", "read", "0 0 1 1 1 0 0 0  0 0 0 x x x x x  0 0 0 0 0 0 0 0  o o o o o o o o"),
+    op!("lfuse", "read", "0 1 0 1 0 0 0 0  0 0 0 0 0 0 0 0  x x x x x x x x  o o o o o o o o"),
+    op!("lfuse", "write", "1 0 1 0 1 1 0 0  1 0 1 0 0 0 0 0  x x x x x x x x  i i i i i i i i"),
+    op!("hfuse", "read", "0 1 0 1 1 0 0 0  0 0 0 0 1 0 0 0  x x x x x x x x  o o o o o o o o"),
+    op!("hfuse", "write", "1 0 1 0 1 1 0 0  1 0 1 0 1 0 0 0  x x x x x x x x  i i i i i i i i"),
+    op!("efuse", "read", "0 1 0 1 0 0 0 0  0 0 0 0 1 0 0 0  x x x x x x x x  o o o o o o o o"),
+    op!("efuse", "write", "1 0 1 0 1 1 0 0  1 0 1 0 0 1 0 0  x x x x x x x x  i i i i i i i i"),
+    op!("lock", "read", "0 1 0 1 1 0 0 0  0 0 0 0 0 0 0 0  x x x x x x x x  x x o o o o o o"),
+    op!("lock", "write", "1 0 1 0 1 1 0 0  1 1 1 x x x x x  x x x x x x x x  1 1 i i i i i i"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::Opcode;
+
+    #[test]
+    fn opcode_instructions() {
+        let read_sig = Opcode::parse("0 0 1 1 0 0 0 0  0 0 0 x x x x x  x x x x x x a1 a0  o o o o o o o o").unwrap();
+        assert_eq!(read_sig.instruction(2, 0), [0x30, 0x00, 0x02, 0x00]);
+        assert_eq!(read_sig.output(0x0f), 0x0f);
+
+        let write_lock = Opcode::parse("1 0 1 0 1 1 0 0  1 1 1 x x x x x  x x x x x x x x  1 1 i i i i i i").unwrap();
+        assert_eq!(write_lock.instruction(0, 0x0f), [0xac, 0xe0, 0x00, 0xcf]);
+
+        let read_lock = Opcode::parse("0 1 0 1 1 0 0 0  0 0 0 0 0 0 0 0  x x x x x x x x  x x o o o o o o").unwrap();
+        assert_eq!(read_lock.output(0xff), 0x3f);
+
+        assert!(Opcode::parse("0 1 0 1").is_err());
+        assert!(Opcode::parse("0 1 0 1 1 0 0 0  0 0 0 0 0 0 0 0  x x x x x x x x  x x o o o o o q").is_err());
+    }
+}
@@ -8,18 +8,52 @@ extern crate tokio_service;
 #[macro_use] extern crate log;
 
 use futures::Future;
-use futures::future::{loop_fn, Loop};
+use futures::future::{self, loop_fn, Either, Loop};
 use futures::sync::oneshot;
 
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+pub mod avrdude_conf;
+mod cancel;
 pub mod codec;
-pub use codec::{Stk500Codec, Client};
-
-pub type Response = Box<Future<Item=Vec<u8>, Error=oneshot::Canceled>>;
+pub mod device;
+pub mod elf;
+mod error;
+mod format;
+pub mod fuse;
+pub mod ihex;
+mod image;
+pub mod isp;
+mod parameter;
+mod progress;
+pub mod srec;
+pub use cancel::CancelHandle;
+pub use codec::{Stk500Codec, Client, ClientConfig, Identity, ProgOptions, RetryPolicy, Reply, check_device_signature, check_signature, is_idempotent};
+pub use device::{Device, DeviceParameters, DeviceParametersExt};
+pub use elf::ElfImage;
+pub use error::StkError;
+pub use format::{FileFormat, binary_to_image, detect_format, load_image};
+pub use fuse::Fuses;
+pub use image::{MemoryImage, Segment};
+pub use parameter::{Parameter, Version};
+pub use progress::{Progress, ProgressCallback};
+
+pub type Response = Box<dyn Future<Item=Vec<u8>, Error=StkError>>;
+
+/// Consecutive in-sync replies needed before a resync is considered done
+const RESYNC_REPLIES: u32 = 3;
+/// Failed `CmndStkGetSync` attempts after which a resync gives up
+const RESYNC_ATTEMPTS: u32 = 10;
+/// How long `Programmer::resync` waits for each reply
+const RESYNC_TIMEOUT_MS: u64 = 500;
+
+/// Delay before resync attempt number `attempt + 1`
+fn resync_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(10 << attempt.min(5))
+}
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Command {
         RespStkOk = 0x10,
         RespStkFailed = 0x11,
@@ -138,6 +172,14 @@ impl Programmer {
         self.inner.lock().unwrap().set_write_cb(callback)
     }
 
+    /// Have `prog_memory` report its progress to `callback`.
+    pub fn set_progress_cb<F>(&mut self, callback: F)
+        where F: Fn(Progress),
+              F: 'static
+    {
+        self.inner.lock().unwrap().progress_cb = Some(ProgressCallback::new(callback));
+    }
+
     pub fn deliver(&mut self, buf: Vec<u8>) {
         self.inner.lock().unwrap().deliver(buf)
     }
@@ -152,16 +194,51 @@ impl Programmer {
         self.inner.lock().unwrap().sign_on()
     }
 
+    /// Get back in sync with the target after a framing error or a reset.
+    ///
+    /// `CmndStkGetSync` is sent repeatedly, backing off after each failure,
+    /// until `RESYNC_REPLIES` consecutive in-sync replies are received.
+    pub fn resync(&mut self) -> Box<dyn Future<Item=(), Error=StkError>> {
+        debug!("Programmer::resync()");
+        let p = self.inner.clone();
+        let f = loop_fn((0, 0), move |(attempt, in_sync)| {
+            let reply = p.lock().unwrap().get_sync();
+            let timeout = futures_timer::Delay::new(Duration::from_millis(RESYNC_TIMEOUT_MS));
+            reply.select2(timeout).then(move |res| -> Box<dyn Future<Item=Loop<(), (u32, u32)>, Error=StkError>> {
+                match res {
+                    Ok(Either::A(_)) if in_sync + 1 >= RESYNC_REPLIES => Box::new(future::ok(Loop::Break(()))),
+                    Ok(Either::A(_)) => Box::new(future::ok(Loop::Continue((attempt, in_sync + 1)))),
+                    Err(Either::B((e, _))) => Box::new(future::err(StkError::from(e))),
+                    res => {
+                        let e = match res {
+                            Err(Either::A((e, _))) => e,
+                            _ => StkError::Timeout,
+                        };
+                        debug!("Programmer::resync() attempt {} failed: {}", attempt, e);
+                        if attempt + 1 >= RESYNC_ATTEMPTS {
+                            return Box::new(future::err(e));
+                        }
+                        let f = futures_timer::Delay::new(resync_backoff(attempt))
+                            .map_err(StkError::from)
+                            .map(move |_| Loop::Continue((attempt + 1, 0)));
+                        Box::new(f)
+                    }
+                }
+            })
+        });
+        Box::new(f)
+    }
+
     pub fn read_sign(&mut self) -> Response {
         debug!("Programmer::read_sign()");
         self.inner.lock().unwrap().read_sign()
     }
 
-    pub fn set_device(&mut self, settings: &Option<Vec<u8>>) -> Response {
+    pub fn set_device(&mut self, settings: &Option<DeviceParameters>) -> Response {
         self.inner.lock().unwrap().set_device(settings)
     }
 
-    pub fn set_device_ext(&mut self, settings: &Option<Vec<u8>>) -> Response {
+    pub fn set_device_ext(&mut self, settings: &Option<DeviceParametersExt>) -> Response {
         self.inner.lock().unwrap().set_device_ext(settings)
     }
 
@@ -177,75 +254,104 @@ impl Programmer {
         self.inner.lock().unwrap().prog_page(mem_type, data)
     }
 
-    pub fn prog_memory(&mut self, mem_type: char, page_size: usize, word_size: usize, data: Vec<u8>) -> 
-        Box< Future<Item=Vec<u8>, Error=oneshot::Canceled> >
+    pub fn prog_memory<I>(&mut self, mem_type: char, page_size: usize, word_size: usize, data: I) -> 
+        Response
+        where I: Into<MemoryImage>
     {
-        let p = self.inner.clone();
+        self.prog_memory_session(None, mem_type, page_size, word_size, data.into())
+    }
+
+    /// Like `prog_memory`, for `device`: its parameters are sent to the
+    /// programmer, and a target with another signature fails the session
+    /// with `StkError::SignatureMismatch` before anything is written.
+    pub fn prog_memory_for<I>(&mut self, device: &Device, mem_type: char, page_size: usize, word_size: usize,
+                              data: I) -> Response
+        where I: Into<MemoryImage>
+    {
+        self.prog_memory_session(Some(device.clone()), mem_type, page_size, word_size, data.into())
+    }
+
+    fn prog_memory_session(&mut self, device: Option<Device>, mem_type: char, page_size: usize, word_size: usize,
+                           data: MemoryImage) -> Response
+    {
+        // Padding an EEPROM page would overwrite the cells in its gaps
+        let pages = if mem_type == 'E' { data.runs(page_size) } else { data.pages(page_size) };
+        // Only 16 bit word addresses can be loaded
+        if let Some(&(address, _)) = pages.iter().find(|&&(address, _)| address as usize / word_size > 0xffff) {
+            return Box::new(future::err(StkError::AddressOutOfRange { address: address as usize }));
+        }
+        let progress = self.inner.lock().unwrap().progress_cb.clone();
         let p2 = self.inner.clone();
         let p3 = self.inner.clone();
         let p4 = self.inner.clone();
         let p5 = self.inner.clone();
+        let p5b = self.inner.clone();
         let p6 = self.inner.clone();
         let p7 = self.inner.clone();
         let p8 = self.inner.clone();
-        let f = p.lock().unwrap().get_sync().and_then(move |_| {
+        let progress4 = progress.clone();
+        let progress5 = progress.clone();
+        let progress7 = progress.clone();
+        let settings = device.as_ref().map(|d| d.parameters());
+        let settings_ext = device.as_ref().map(|d| d.parameters_ext());
+        let f = self.resync().and_then(move |_| {
             let mut inner = p2.lock().unwrap();
-            inner.set_device(&None)
+            inner.set_device(&settings)
         }).and_then(move |_| {
             let mut inner = p3.lock().unwrap();
-            inner.set_device_ext(&None)
+            inner.set_device_ext(&settings_ext)
         }).and_then(move |_| {
             let mut inner = p4.lock().unwrap();
             inner.enter_prog_mode()
+        }).and_then(move |resp| {
+            progress::report(&progress4, Progress::Connected);
+            Ok(resp)
         }).and_then(move |_| {
             let mut inner = p5.lock().unwrap();
             inner.read_sign()
-        }).and_then(move |_| {
-            fn check_program(buf: &Vec<u8>) -> bool {
-                buf.iter().any(|&x| {
-                    x != 0xff
-                })
+        }).and_then(move |resp| -> Response {
+            // RespStkInsync, the signature, then RespStkOk
+            if resp.len() >= 5 {
+                progress::report(&progress5, Progress::SignatureRead([resp[1], resp[2], resp[3]]));
             }
-            
-            loop_fn(0 as usize, move |mut index| {
-                let mut end = index + page_size;
-                if end >= data.len() {
-                    end = data.len();
+            match device.map(|device| check_device_signature(&device, &resp[1..resp.len() - 1])) {
+                Some(Err(e)) => {
+                    // Don't leave the target stuck in programming mode
+                    let mut inner = p5b.lock().unwrap();
+                    Box::new(inner.leave_prog_mode().then(move |_| Err(e)))
                 }
-                let mut page = &data[index..end];
-                let _data = data.clone();
-                let __data = data.clone();
-                while check_program(&page.to_vec()) == false {
-                    index = end;
-                    if index > data.len() {
-                        break;
-                    }
-                    end = index + page_size;
-                    if end >= data.len() {
-                        end = data.len();
-                    }
-                    page = &data[index..end];
+                _ => Box::new(future::ok(resp)),
+            }
+        }).and_then(move |_| {
+            let total = pages.iter().map(|(_, page)| page.len()).sum::<usize>();
+            loop_fn((0, 0), move |(index, bytes)| -> Box<dyn Future<Item=Loop<(), (usize, usize)>, Error=StkError>> {
+                if index >= pages.len() {
+                    return Box::new(future::ok(Loop::Break(())));
                 }
+                let (address, ref page) = pages[index];
+                let page = page.clone();
+                let bytes = bytes + page.len();
                 let p6 = p6.clone();
                 let p7 = p7.clone();
-                futures_timer::Delay::new(Duration::from_millis(50)).map_err(|_|{oneshot::Canceled}).and_then( move |_| {
+                let progress = progress7.clone();
+                let f = futures_timer::Delay::new(Duration::from_millis(50)).map_err(StkError::from).and_then( move |_| {
                     let mut inner = p6.lock().unwrap();
-                    inner.load_address((index / word_size) as u16)
+                    inner.load_address((address as usize / word_size) as u16)
                 }).and_then(move |_| {
                     let mut inner = p7.lock().unwrap();
-                    let ref page = _data[index..end];
-                    inner.prog_page(mem_type, &page.to_vec())
-                }).and_then(move |_| {
-                    if end < __data.len() {
-                        Ok(Loop::Continue(end))
-                    } else {
-                        Ok(Loop::Break(end))
-                    }
-                })
+                    inner.prog_page(mem_type, &page)
+                }).map(move |_| {
+                    progress::report(&progress, Progress::PageWritten { address: address as usize, bytes, total });
+                    Loop::Continue((index + 1, bytes))
+                });
+                Box::new(f)
             })
         }).and_then(move |_| {
             let mut inner = p8.lock().unwrap();
             inner.leave_prog_mode()
+        }).map(move |resp| {
+            progress::report(&progress, Progress::Done);
+            resp
         });
         Box::new(f)
     }
@@ -255,7 +361,8 @@ struct Inner {
     write_cb: Option< Box<Fn(Vec<u8>)> >, // Function which writes to the microcontroller being programmed
     buffer: Vec<u8>,
     state: State,
-    waiting_future: Option<oneshot::Sender<Vec<u8>>>,
+    waiting_future: Option<oneshot::Sender<Result<Vec<u8>, StkError>>>,
+    progress_cb: Option<ProgressCallback>,
 }
 
 impl Inner {
@@ -265,7 +372,8 @@ impl Inner {
             write_cb: None, 
             buffer: Vec::new() ,
             state: State::Idle,
-            waiting_future: None
+            waiting_future: None,
+            progress_cb: None,
         }
     }
 
@@ -280,6 +388,26 @@ impl Inner {
         debug!("deliver() received {} bytes", buf.len());
         self.buffer.extend(buf.iter());
         debug!("Current deliver buffer size: {}", self.buffer.len());
+        if let State::Idle = self.state {
+            debug!("No command pending. Discarding internal buffer...");
+            self.buffer.clear();
+            return;
+        }
+        if self.buffer.first() == Some(&(Command::RespStkNosync as u8)) {
+            debug!("Target is out of sync. Resetting internal buffer...");
+            self.buffer.clear();
+            self.respond(Err(StkError::OutOfSync));
+            return;
+        }
+        // Anything in front of the header byte is line noise or leftover
+        // output from the target.
+        let skip = self.buffer.iter()
+            .position(|&b| b == Command::RespStkInsync as u8)
+            .unwrap_or(self.buffer.len());
+        if skip > 0 {
+            debug!("Discarding {} bytes in front of the header byte...", skip);
+            self.buffer.drain(..skip);
+        }
         if self.buffer.len() < 2 {
             debug!("Buffer too small. Wait for more data.");
             return;
@@ -290,31 +418,20 @@ impl Inner {
                 return;
             }
         }
-        let byte = *self.buffer.first().unwrap();
-        if byte != Command::RespStkInsync as u8 {
-            debug!("Buffer header byte incorrect. Resetting internal buffer...");
-            self.buffer.clear();
-			let _maybe_sender = self.waiting_future.take();
-            self.state = State::Idle;
-            return;
-        }
-        match self.state {
-            State::Idle => {
-            }
-            State::WaitResponse => {
-                let maybe_sender = self.waiting_future.take();
-                if let Some(sender) = maybe_sender {
-                    sender.send(self.buffer.split_off(0)).unwrap();
-                }
-                self.state = State::Idle;
-                self.buffer.clear();
-            }
+        let response = self.buffer.split_off(0);
+        self.respond(Ok(response));
+    }
+
+    fn respond(&mut self, response: Result<Vec<u8>, StkError>) {
+        if let Some(sender) = self.waiting_future.take() {
+            let _ = sender.send(response);
         }
+        self.state = State::Idle;
     }
 
     fn send_command(&mut self, buf: &Vec<u8>) -> Response {
         self.state = State::WaitResponse;
-        let (tx, rx) = oneshot::channel::<Vec<u8>>();
+        let (tx, rx) = oneshot::channel::<Result<Vec<u8>, StkError>>();
         self.waiting_future = Some(tx);
         if let Some(ref cb) = self.write_cb {
             let mut _buf = buf.clone();
@@ -322,7 +439,10 @@ impl Inner {
             debug!("Programmer::send_command: {} bytes.", _buf.len());
             cb(_buf);
         }
-        Box::new(rx)
+        Box::new(rx.then(|r| match r {
+            Ok(response) => response,
+            Err(canceled) => Err(StkError::from(canceled)),
+        }))
     }
 
     pub fn get_sync(&mut self) -> Response {
@@ -340,24 +460,22 @@ impl Inner {
         self.send_command(&vec![Command::CmndStkReadSign as u8])
     }
 
-    pub fn set_device(&mut self, settings: &Option<Vec<u8>>) -> Response {
+    pub fn set_device(&mut self, settings: &Option<DeviceParameters>) -> Response {
         debug!("Programmer::set_device()");
         let s = match *settings {
-            Some(ref buf) => buf.clone(),
-            None => vec![0x86, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01,
-                0x03, 0xff, 0xff, 0xff, 0xff, 0x00, 0x80, 0x04, 0x00,
-                0x00, 0x00, 0x80, 0x00]
+            Some(ref parameters) => parameters.to_bytes(),
+            None => device::default_device().parameters().to_bytes()
         };
         let mut command = vec![Command::CmndStkSetDevice as u8];
         command.extend(s);
         self.send_command(&command)
     }
 
-    pub fn set_device_ext(&mut self, settings: &Option<Vec<u8>>) -> Response {
+    pub fn set_device_ext(&mut self, settings: &Option<DeviceParametersExt>) -> Response {
         debug!("Programmer::set_device_ext()");
         let s = match *settings {
-            Some(ref buf) => buf.clone(),
-            None => vec![0x05, 0x04, 0xd7, 0xc2, 0x00]
+            Some(ref parameters) => parameters.to_bytes(),
+            None => device::default_device().parameters_ext().to_bytes()
         };
         let mut command = vec![Command::CmndStkSetDeviceExt as u8];
         command.extend(s);
@@ -394,86 +512,82 @@ impl Inner {
     }
 }
 
-#[derive(Debug)]
-pub enum StkError {
-    ParseHexFileError,
+/// Parse an Intel HEX file into a buffer starting at address 0. Gaps are
+/// filled with 0xff; use `hex_to_image` to keep them apart from real data.
+pub fn hex_to_buffer(hex_string: &str) -> Result<Vec<u8>, StkError> {
+    Ok(hex_to_image(hex_string)?.to_buffer())
 }
 
-impl From<std::num::ParseIntError> for StkError {
-    fn from(_: std::num::ParseIntError) -> StkError {
-        StkError::ParseHexFileError
-    }
+/// Parse an Intel HEX file into a sparse memory image.
+pub fn hex_to_image(hex_string: &str) -> Result<MemoryImage, StkError> {
+    Ok(ihex::parse(hex_string)?.image)
 }
 
-pub fn hex_to_buffer(hex_string: &String) -> Result<Vec<u8>, StkError> {
-    fn string_to_u32(s: Option<&str>) -> Result<u32, StkError> {
-        match s {
-            None => {
-                return Err(StkError::ParseHexFileError)
-            }
-            Some(digits) => {
-                let num = u32::from_str_radix(digits, 16)?;
-                Ok(num)
-            }
-        }
-    }
-
-    fn process_line(line: &str) -> Result<(u32, u32, u32), StkError>
-    {
-        // Returns byte_count, addr, and record_type
-        let byte_count = string_to_u32(line.get(1..3))?;
-        let addr = string_to_u32(line.get(3..7))?;
-        let record_type = string_to_u32(line.get(7..9))?;
-        Ok((byte_count, addr, record_type))
-    }
-    // Calculate the maximum size of the firmware file
-    let mut base_address = 0;
-    let mut size = 0;
-    for line in hex_string.lines() {
-        let (byte_count, addr, record_type) = process_line(line)?;
-        if record_type == 2 {
-            let ext = string_to_u32(line.get(9..((9+byte_count*2) as usize)))?;
-            base_address = ext*16;
-        } else if record_type == 4 {
-            let ext = string_to_u32(line.get(9..((9+byte_count*2) as usize)))?;
-            base_address = ext << 16;
-        } else if record_type == 0 {
-            let new_size = addr + base_address + byte_count;
-            if new_size > size {
-                size = new_size;
-            }
-        }
-    }
-
-    let mut buffer:Vec<u8> = vec![0xff; size as usize];
-
-    base_address = 0;
-    for line in hex_string.lines() {
-        let (byte_count, addr, record_type) = process_line(line)?;
-        if record_type == 2 {
-            let ext = string_to_u32(line.get(9..((9+byte_count*2) as usize)))?;
-            base_address = ext*16;
-        } else if record_type == 4 {
-            let ext = string_to_u32(line.get(9..((9+byte_count*2) as usize)))?;
-            base_address = ext << 16;
-        } else if record_type == 0 {
-            let _addr = addr + base_address;
-            for i in 0..byte_count {
-                let b = string_to_u32(line.get( ((9+i*2) as usize) .. ((11+(i*2)) as usize)))?;
-                buffer[(_addr + i) as usize] = b as u8;
-            }
-        }
-    }
+/// Write a buffer starting at address 0 out as Intel HEX, the same way
+/// avrdude does.
+pub fn buffer_to_hex(buffer: &[u8]) -> String {
+    let mut image = MemoryImage::new();
+    image.insert(0, buffer);
+    image_to_hex(&image)
+}
 
-    Ok(buffer)
+/// Write a memory image out as Intel HEX, the same way avrdude does.
+pub fn image_to_hex(image: &MemoryImage) -> String {
+    let file = ihex::HexFile { image: image.clone(), entry_point: None };
+    ihex::write(&file, 32, ihex::Addressing::Segment)
 }
 
 #[cfg(test)]
 mod tests {
-    use futures::Future;
-    use super::Client;
+    use futures::{executor, Async, Future};
+    use super::{device, Client, Command, MemoryImage, Programmer, Response, StkError};
+    use std::cell::RefCell;
     use std::fs::File;
     use std::io::{Read};
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    struct NoNotify;
+
+    impl executor::Notify for NoNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    /// Poll `session` to completion, answering each command `programmer`
+    /// sends like an ATmega328P would. Returns the commands sent.
+    fn run_session<F>(programmer: &mut Programmer, session: F) -> (Result<Vec<u8>, StkError>, Vec<Vec<u8>>)
+        where F: FnOnce(&mut Programmer) -> Response
+    {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let _sent = sent.clone();
+        programmer.set_write_cb(move |command| _sent.borrow_mut().push(command));
+        let mut session = executor::spawn(session(programmer));
+        let notify = executor::NotifyHandle::from(&NoNotify as &'static NoNotify);
+        let mut answered = 0;
+        let result = loop {
+            match session.poll_future_notify(&notify, 0) {
+                Ok(Async::Ready(resp)) => break Ok(resp),
+                Err(e) => break Err(e),
+                Ok(Async::NotReady) => {}
+            }
+            let command = sent.borrow().get(answered).cloned();
+            match command {
+                Some(command) => {
+                    answered += 1;
+                    let mut reply = vec![Command::RespStkInsync as u8];
+                    if command[0] == Command::CmndStkReadSign as u8 {
+                        reply.extend_from_slice(&[0x1e, 0x95, 0x0f]);
+                    }
+                    reply.push(Command::RespStkOk as u8);
+                    programmer.deliver(reply);
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        let sent = sent.borrow().clone();
+        (result, sent)
+    }
 
     #[test]
     fn async_test() {
@@ -512,4 +626,46 @@ mod tests {
         settings.baud_rate = tokio_serial::BaudRate::Baud115200;
         let port = tokio_serial::Serial::from_path("/dev/ttyACM0", &settings, &handle).unwrap();
     }
+
+    #[test]
+    fn deliver_skips_garbage() {
+        let mut programmer = Programmer::new();
+        programmer.set_write_cb(|_| {});
+        let response = programmer.get_sync();
+        programmer.deliver(b"Hello".to_vec());
+        programmer.deliver(vec![Command::RespStkInsync as u8, Command::RespStkOk as u8]);
+        assert_eq!(response.wait().unwrap(),
+                   vec![Command::RespStkInsync as u8, Command::RespStkOk as u8]);
+    }
+
+    #[test]
+    fn prog_memory_address_out_of_range() {
+        let mut programmer = Programmer::new();
+        programmer.set_write_cb(|_| panic!("Nothing should be sent"));
+        let mut image = MemoryImage::new();
+        image.insert(0x3f000, &[1, 2]);
+        match programmer.prog_memory('F', 256, 2, image).wait() {
+            Err(StkError::AddressOutOfRange { address: 0x3f000 }) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn prog_memory_for_checks_signature() {
+        let mut programmer = Programmer::new();
+        let m328p = device::by_name("m328p").unwrap();
+        let (result, sent) = run_session(&mut programmer, |p| p.prog_memory_for(m328p, 'F', 128, 2, vec![1, 2]));
+        result.unwrap();
+        assert!(sent.iter().any(|command| command[0] == Command::CmndStkProgPage as u8));
+
+        let m32u4 = device::by_name("m32u4").unwrap();
+        let (result, sent) = run_session(&mut programmer, |p| p.prog_memory_for(m32u4, 'F', 128, 2, vec![1, 2]));
+        match result {
+            Err(StkError::SignatureMismatch { expected_part: Some(ref name), actual: [0x1e, 0x95, 0x0f], .. })
+                if name == "ATmega32U4" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(!sent.iter().any(|command| command[0] == Command::CmndStkProgPage as u8));
+        assert_eq!(sent.last().unwrap()[0], Command::CmndStkLeaveProgmode as u8);
+    }
 }
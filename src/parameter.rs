@@ -0,0 +1,53 @@
+use std::fmt;
+use super::Command;
+
+/// A programmer parameter, read with `CmndStkGetParameter` and written with
+/// `CmndStkSetParameter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parameter {
+    HwVer = Command::ParmStkHwVer as isize,
+    SwMajor = Command::ParmStkSwMajor as isize,
+    SwMinor = Command::ParmStkSwMinor as isize,
+    Leds = Command::ParmStkLeds as isize,
+    /// Target voltage in tenths of a volt
+    Vtarget = Command::ParmStkVtarget as isize,
+    /// Adjustable voltage reference in tenths of a volt
+    Vadjust = Command::ParmStkVadjust as isize,
+    OscPscale = Command::ParmStkOscPscale as isize,
+    OscCmatch = Command::ParmStkOscCmatch as isize,
+    ResetDuration = Command::ParmStkResetDuration as isize,
+    SckDuration = Command::ParmStkSckDuration as isize,
+    Bufsizel = Command::ParmStkBufsizel as isize,
+    Bufsizeh = Command::ParmStkBufsizeh as isize,
+    Device = Command::ParmStkDevice as isize,
+    Progmode = Command::ParmStkProgmode as isize,
+    Paramode = Command::ParmStkParamode as isize,
+    Polling = Command::ParmStkPolling as isize,
+    Selftimed = Command::ParmStkSelftimed as isize,
+}
+
+/// A firmware version, as reported by `ParmStkSwMajor` and `ParmStkSwMinor`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Parameter, Version};
+
+    #[test]
+    fn parameter_codes() {
+        assert_eq!(Parameter::HwVer as u8, 0x80);
+        assert_eq!(Parameter::SckDuration as u8, 0x89);
+        assert_eq!(Parameter::Selftimed as u8, 0x96);
+        assert_eq!(Version { major: 4, minor: 4 }.to_string(), "4.4");
+    }
+}
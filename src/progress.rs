@@ -0,0 +1,47 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// A step of a `prog_memory` session.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Progress {
+    /// In sync with the target, which is now in programming mode
+    Connected,
+    SignatureRead([u8; 3]),
+    /// The page at byte address `address` was written. `bytes` counts the
+    /// bytes written so far in this session, out of `total`.
+    PageWritten { address: usize, bytes: usize, total: usize },
+    /// The page at byte address `address` was read back and matched
+    Verified { address: usize },
+    /// Programming mode was left and the session is over
+    Done,
+}
+
+/// A callback receiving the `Progress` of a programming session.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(Progress)>);
+
+impl ProgressCallback {
+    pub fn new<F>(callback: F) -> ProgressCallback
+        where F: Fn(Progress),
+              F: 'static
+    {
+        ProgressCallback(Arc::new(callback))
+    }
+
+    pub fn report(&self, progress: Progress) {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressCallback")
+    }
+}
+
+/// Report `progress` to `callback`, if there is one.
+pub(crate) fn report(callback: &Option<ProgressCallback>, progress: Progress) {
+    if let Some(ref callback) = *callback {
+        callback.report(progress);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{MemoryImage, StkError};
+use super::ihex::hex_digit;
+
+/// The contents of a Motorola S-record file.
+#[derive(Debug, Default)]
+pub struct SrecFile {
+    pub image: MemoryImage,
+    /// Execution start address from the S7, S8 or S9 record
+    pub entry_point: Option<u32>,
+}
+
+enum Record {
+    Header,
+    Data { address: u32, data: Vec<u8> },
+    Count,
+    Termination { entry_point: u32 },
+}
+
+/// Parse a Motorola S-record file. Every record's checksum is checked, and the
+/// file must be terminated by an S7, S8 or S9 record.
+pub fn parse(srec_string: &str) -> Result<SrecFile, StkError> {
+    let mut file = SrecFile::default();
+    let mut lines = 0;
+    for (line_no, line) in srec_string.lines().enumerate() {
+        lines = line_no + 1;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let record = parse_record(line).map_err(|(column, reason)| {
+            StkError::ParseSrecError { line: line_no + 1, column, reason }
+        })?;
+        match record {
+            Record::Header | Record::Count => {}
+            Record::Data { address, data } => {
+                if address.checked_add(data.len() as u32).is_none() {
+                    return Err(StkError::ParseSrecError {
+                        line: line_no + 1, column: 5, reason: "data runs past the end of the address space",
+                    });
+                }
+                file.image.insert(address, &data);
+            }
+            Record::Termination { entry_point } => {
+                file.entry_point = Some(entry_point);
+                return Ok(file);
+            }
+        }
+    }
+    Err(StkError::ParseSrecError { line: lines + 1, column: 1, reason: "missing termination record" })
+}
+
+/// Parse a single record. Errors carry the 1-based column they were found at.
+fn parse_record(line: &str) -> Result<Record, (usize, &'static str)> {
+    if !line.starts_with('S') {
+        return Err((1, "missing 'S' start code"));
+    }
+    let record_type = line.as_bytes().get(1).cloned().and_then(hex_digit)
+        .ok_or((2, "invalid record type"))?;
+    let digits = &line.as_bytes()[2..];
+    if digits.len() & 1 == 1 {
+        return Err((line.len(), "odd number of hex digits"));
+    }
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    for (i, pair) in digits.chunks(2).enumerate() {
+        let hi = hex_digit(pair[0]).ok_or((3 + i * 2, "invalid hex digit"))?;
+        let lo = hex_digit(pair[1]).ok_or((4 + i * 2, "invalid hex digit"))?;
+        bytes.push(hi << 4 | lo);
+    }
+    if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+        return Err((3, "byte count doesn't match record length"));
+    }
+    let checksum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    if checksum != 0xff {
+        return Err((line.len() - 1, "checksum mismatch"));
+    }
+
+    let address_len = match record_type {
+        0 | 1 | 5 | 9 => 2,
+        2 | 6 | 8 => 3,
+        3 | 7 => 4,
+        _ => return Err((2, "unknown record type")),
+    };
+    // Byte count, address, checksum
+    if bytes.len() < address_len + 2 {
+        return Err((3, "record too short"));
+    }
+    let address = bytes[1..1 + address_len].iter().fold(0u32, |address, &b| address << 8 | b as u32);
+    let data = &bytes[1 + address_len..bytes.len() - 1];
+    match record_type {
+        0 => Ok(Record::Header),
+        1..=3 => Ok(Record::Data { address, data: data.to_vec() }),
+        5 | 6 => Ok(Record::Count),
+        _ => Ok(Record::Termination { entry_point: address }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use super::super::StkError;
+
+    #[test]
+    fn parse_records() {
+        let srec = "S00600004844521B\n\
+                    S107001001020304DE\n\
+                    S20800FFFE05060708E0\n\
+                    S5030002FA\n\
+                    S9030010EC\n";
+        let file = parse(srec).unwrap();
+        assert_eq!(file.image.get(0x10), Some(1));
+        assert_eq!(file.image.get(0xfffe), Some(5));
+        assert_eq!(file.image.get(0x10001), Some(8));
+        assert_eq!(file.entry_point, Some(0x10));
+    }
+
+    #[test]
+    fn parse_errors() {
+        fn position(srec: &str) -> (usize, usize) {
+            match parse(srec) {
+                Err(StkError::ParseSrecError { line, column, .. }) => (line, column),
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+        // Bad checksum
+        assert_eq!(position("S107001001020304DF\nS9030010EC\n"), (1, 17));
+        // Truncated file
+        assert_eq!(position("S107001001020304DE\n"), (2, 1));
+        // Data past 0xffffffff
+        assert_eq!(position("S307FFFFFFFF0102F9\nS9030010EC\n"), (1, 5));
+    }
+}